@@ -18,7 +18,7 @@ use tungstenite::{accept, WebSocket, Message, handshake};
 use tungstenite::HandshakeError::{self, Interrupted};
 use tungstenite::util::NonBlockingError;
 
-use common::{Vote, StateChange, Action};
+use common::{Ballot, StateChange, Action, Hello, Message as Protocol};
 
 use std::fs::File;
 use std::path::Path;
@@ -42,27 +42,46 @@ fn main() {
 
         let mut websocket = tungstenite::connect(request).unwrap().0;
 
+        let hello = serde_json::to_string(&Protocol::Hello(Hello::new(Vec::new(), false))).unwrap();
+        websocket.write_message(Message::text(hello));
+
+        sockets.push(websocket);
+    }
+
+    // ballots have to name the round, so every socket waits for the vote call
+    for websocket in &mut sockets {
+        let round = wait_for_round(websocket);
+
         let x1 = rand::thread_rng().gen_range(0, 8);
         let x2 = rand::thread_rng().gen_range(0, 8);
         let y1 = rand::thread_rng().gen_range(0, 8);
         let y2 = rand::thread_rng().gen_range(0, 8);
-        let v = Vote {
+        let v = Ballot {
             action: Action {
                 from: (x1, y1),
                 to: (x2, y2),
             },
-            weight: 1,
+            round: round,
         };
 
-        let string = serde_json::to_string(&v).unwrap();
+        let string = serde_json::to_string(&Protocol::Ballot(v)).unwrap();
         let message = Message::text(string);
         
         websocket.write_message(message);
         websocket.write_pending();
+    }
 
-        sockets.push(websocket);
+    loop {
     }
+}
 
+/// Reads from the funnel until it calls a vote, returning the round.
+fn wait_for_round<S: Read + Write>(websocket: &mut WebSocket<S>) -> u64 {
     loop {
+        if let Message::Text(text) = websocket.read_message().unwrap() {
+            if let Ok(Protocol::VoteCall(vote_call)) = serde_json::from_str(&text) {
+                return vote_call.round;
+            }
+        }
     }
 }
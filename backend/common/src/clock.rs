@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use Pong;
+
+/// How many recent exchanges are kept when picking the best estimate.
+const SAMPLES: usize = 8;
+
+/// Milliseconds since the UNIX epoch on the local clock.
+pub fn now_millis() -> u64 {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before 1970");
+    since.as_secs() * 1000 + since.subsec_nanos() as u64 / 1_000_000
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Sample {
+    round_trip: u64,
+    offset: i64,
+}
+
+/// Estimates how far a peer's clock is ahead of ours from a series of `Ping`/`Pong`
+/// exchanges. Each exchange assumes the reply was stamped halfway through the round trip,
+/// so the sample with the shortest round trip among the recent ones is trusted the most.
+#[derive(Debug, Clone)]
+pub struct ClockOffset {
+    samples: VecDeque<Sample>,
+}
+
+impl Default for ClockOffset {
+    fn default() -> ClockOffset {
+        ClockOffset::new()
+    }
+}
+
+impl ClockOffset {
+    pub fn new() -> ClockOffset {
+        ClockOffset {
+            samples: VecDeque::with_capacity(SAMPLES),
+        }
+    }
+
+    /// Records the reply to one of our pings, received at local time `now`.
+    pub fn record(&mut self, pong: &Pong, now: u64) {
+        if now < pong.sent {
+            // our own clock jumped backwards, so this exchange tells us nothing
+            return;
+        }
+        let round_trip = now - pong.sent;
+        let midpoint = pong.sent + round_trip / 2;
+        let offset = pong.received as i64 - midpoint as i64;
+
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample { round_trip, offset });
+    }
+
+    fn best(&self) -> Option<Sample> {
+        self.samples.iter().min_by_key(|sample| sample.round_trip).cloned()
+    }
+
+    /// Milliseconds the peer's clock is ahead of ours, or zero before the first exchange.
+    pub fn offset(&self) -> i64 {
+        self.best().map(|sample| sample.offset).unwrap_or(0)
+    }
+
+    /// The round trip time of the exchange the offset was taken from.
+    pub fn round_trip(&self) -> Option<u64> {
+        self.best().map(|sample| sample.round_trip)
+    }
+
+    /// Converts a timestamp on the peer's clock into one on ours.
+    pub fn to_local(&self, peer_time: u64) -> u64 {
+        (peer_time as i64 - self.offset()).max(0) as u64
+    }
+}
+
+/// A smoothed round trip time and its variation, updated the same way TCP sizes its
+/// retransmission timeout (RFC 6298).
+#[derive(Debug, Copy, Clone)]
+pub struct RoundTrip {
+    smoothed: Option<f64>,
+    variation: f64,
+}
+
+impl Default for RoundTrip {
+    fn default() -> RoundTrip {
+        RoundTrip::new()
+    }
+}
+
+impl RoundTrip {
+    pub fn new() -> RoundTrip {
+        RoundTrip {
+            smoothed: None,
+            variation: 0.0,
+        }
+    }
+
+    /// Records one measured round trip, in milliseconds.
+    pub fn record(&mut self, sample: u64) {
+        let sample = sample as f64;
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(sample);
+                self.variation = sample / 2.0;
+            }
+            Some(smoothed) => {
+                self.variation = 0.75 * self.variation + 0.25 * (smoothed - sample).abs();
+                self.smoothed = Some(0.875 * smoothed + 0.125 * sample);
+            }
+        }
+    }
+
+    /// The smoothed round trip in milliseconds, if anything has been measured yet.
+    pub fn smoothed(&self) -> Option<u64> {
+        self.smoothed.map(|smoothed| smoothed.round() as u64)
+    }
+
+    /// The smoothed mean deviation of the round trip in milliseconds.
+    pub fn variation(&self) -> u64 {
+        self.variation.round() as u64
+    }
+
+    /// How long a message is expected to take in one direction, padded by four times the
+    /// variation so that almost every message arrives within it.
+    pub fn one_way_margin(&self) -> Option<u64> {
+        self.smoothed
+            .map(|smoothed| (smoothed / 2.0 + 4.0 * self.variation).ceil() as u64)
+    }
+}
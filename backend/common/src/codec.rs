@@ -0,0 +1,440 @@
+use std::fmt;
+
+use serde_json;
+
+use {Action, Board, Message, Piece, PieceColour, PieceKind, Ping, Pong, SquareChange,
+     StateChange, StateDelta, Vote, VoteCall};
+
+const STATE_CHANGE: u8 = 1;
+const VOTE_CALL: u8 = 2;
+const VOTE: u8 = 3;
+const PING: u8 = 4;
+const PONG: u8 = 5;
+const STATE_DELTA: u8 = 6;
+/// Anything without a compact layout is sent as JSON after this tag.
+const JSON: u8 = 0xff;
+
+/// Why a binary message could not be decoded.
+#[derive(Debug)]
+pub enum DecodeError {
+    Truncated,
+    UnknownTag(u8),
+    InvalidSquare(u8),
+    InvalidColour(u8),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Truncated => write!(f, "message is truncated"),
+            DecodeError::UnknownTag(tag) => write!(f, "unknown message tag {}", tag),
+            DecodeError::InvalidSquare(square) => write!(f, "invalid square {:#x}", square),
+            DecodeError::InvalidColour(colour) => write!(f, "invalid colour {}", colour),
+            DecodeError::Json(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn colour_to_byte(colour: PieceColour) -> u8 {
+    match colour {
+        PieceColour::White => 0,
+        PieceColour::Black => 1,
+    }
+}
+
+fn colour_from_byte(byte: u8) -> Result<PieceColour, DecodeError> {
+    match byte {
+        0 => Ok(PieceColour::White),
+        1 => Ok(PieceColour::Black),
+        _ => Err(DecodeError::InvalidColour(byte)),
+    }
+}
+
+/// Packs a square into a nibble: zero when empty, otherwise the piece kind counting from one
+/// with the high bit set for black.
+fn square_to_nibble(square: Option<Piece>) -> u8 {
+    match square {
+        None => 0,
+        Some(Piece { kind, colour }) => 1 + kind as u8 | colour_to_byte(colour) << 3,
+    }
+}
+
+fn square_from_nibble(nibble: u8) -> Result<Option<Piece>, DecodeError> {
+    let kind = match nibble & 0x7 {
+        0 if nibble == 0 => return Ok(None),
+        1 => PieceKind::King,
+        2 => PieceKind::Queen,
+        3 => PieceKind::Bishop,
+        4 => PieceKind::Knight,
+        5 => PieceKind::Rook,
+        6 => PieceKind::Pawn,
+        _ => return Err(DecodeError::InvalidSquare(nibble)),
+    };
+    Ok(Some(Piece {
+        kind,
+        colour: colour_from_byte(nibble >> 3)?,
+    }))
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend((0..4).map(|i| (value >> (24 - 8 * i)) as u8));
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend((0..8).map(|i| (value >> (56 - 8 * i)) as u8));
+}
+
+/// Packs the board into 32 bytes, two squares per byte in row major order.
+fn put_board(buffer: &mut Vec<u8>, board: &Board) {
+    for row in board.0.iter() {
+        for pair in row.chunks(2) {
+            buffer.push(square_to_nibble(pair[0]) << 4 | square_to_nibble(pair[1]));
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < count {
+            return Err(DecodeError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(self.take(4)?.iter().fold(0, |value, &byte| value << 8 | byte as u32))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(self.take(8)?.iter().fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    fn board(&mut self) -> Result<Board, DecodeError> {
+        let packed = self.take(32)?;
+        let mut inner = [[None; 8]; 8];
+        for (index, &byte) in packed.iter().enumerate() {
+            let (y, x) = (index / 4, index % 4 * 2);
+            inner[y][x] = square_from_nibble(byte >> 4)?;
+            inner[y][x + 1] = square_from_nibble(byte & 0xf)?;
+        }
+        Ok(Board(inner))
+    }
+}
+
+/// Encodes a message for a link that negotiated `Encoding::Binary`.
+pub fn encode(message: &Message) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    match *message {
+        Message::StateChange(ref state) => {
+            buffer.push(STATE_CHANGE);
+            put_board(&mut buffer, &state.board);
+            buffer.push(colour_to_byte(state.turn));
+            put_u64(&mut buffer, state.sequence);
+        }
+        Message::StateDelta(ref delta) if delta.changes.len() <= 64 => {
+            buffer.push(STATE_DELTA);
+            put_u64(&mut buffer, delta.base);
+            put_u64(&mut buffer, delta.sequence);
+            buffer.push(colour_to_byte(delta.turn));
+            buffer.push(delta.changes.len() as u8);
+            for change in &delta.changes {
+                buffer.push(change.y << 3 | change.x & 0x7);
+                buffer.push(square_to_nibble(change.piece));
+            }
+        }
+        Message::VoteCall(ref vote_call) => {
+            buffer.push(VOTE_CALL);
+            put_u64(&mut buffer, vote_call.round);
+            put_u64(&mut buffer, vote_call.position);
+            put_u64(&mut buffer, vote_call.deadline);
+        }
+        Message::Vote(ref vote) => {
+            buffer.push(VOTE);
+            let Action { from, to } = vote.action;
+            buffer.extend(&[from.0, from.1, to.0, to.1]);
+            put_u32(&mut buffer, vote.weight);
+            match vote.round {
+                Some(round) => {
+                    buffer.push(1);
+                    put_u64(&mut buffer, round);
+                }
+                None => buffer.push(0),
+            }
+        }
+        Message::Ping(ref ping) => {
+            buffer.push(PING);
+            put_u64(&mut buffer, ping.sent);
+        }
+        Message::Pong(ref pong) => {
+            buffer.push(PONG);
+            put_u64(&mut buffer, pong.sent);
+            put_u64(&mut buffer, pong.received);
+        }
+        _ => {
+            buffer.push(JSON);
+            serde_json::to_writer(&mut buffer, message).expect("Messages always serialize");
+        }
+    }
+    buffer
+}
+
+/// Decodes a message produced by `encode`.
+pub fn decode(bytes: &[u8]) -> Result<Message, DecodeError> {
+    let mut reader = Reader { bytes };
+    let message = match reader.u8()? {
+        STATE_CHANGE => Message::StateChange(StateChange {
+            board: reader.board()?,
+            turn: colour_from_byte(reader.u8()?)?,
+            sequence: reader.u64()?,
+        }),
+        STATE_DELTA => {
+            let base = reader.u64()?;
+            let sequence = reader.u64()?;
+            let turn = colour_from_byte(reader.u8()?)?;
+            let count = reader.u8()?;
+            let mut changes = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let square = reader.u8()?;
+                changes.push(SquareChange {
+                    x: square & 0x7,
+                    y: square >> 3 & 0x7,
+                    piece: square_from_nibble(reader.u8()?)?,
+                });
+            }
+            Message::StateDelta(StateDelta {
+                base,
+                sequence,
+                turn,
+                changes,
+            })
+        }
+        VOTE_CALL => Message::VoteCall(VoteCall {
+            round: reader.u64()?,
+            position: reader.u64()?,
+            deadline: reader.u64()?,
+        }),
+        VOTE => {
+            let coordinates = reader.take(4)?;
+            let action = Action {
+                from: (coordinates[0], coordinates[1]),
+                to: (coordinates[2], coordinates[3]),
+            };
+            let weight = reader.u32()?;
+            let round = match reader.u8()? {
+                0 => None,
+                _ => Some(reader.u64()?),
+            };
+            Message::Vote(Vote {
+                action,
+                weight,
+                round,
+            })
+        }
+        PING => Message::Ping(Ping {
+            sent: reader.u64()?,
+        }),
+        PONG => Message::Pong(Pong {
+            sent: reader.u64()?,
+            received: reader.u64()?,
+        }),
+        JSON => return serde_json::from_slice(reader.bytes).map_err(DecodeError::Json),
+        tag => return Err(DecodeError::UnknownTag(tag)),
+    };
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Hello, Lineage, Ancestor};
+
+    fn board() -> Board {
+        let mut inner = [[None; 8]; 8];
+        let kinds = [
+            PieceKind::King,
+            PieceKind::Queen,
+            PieceKind::Bishop,
+            PieceKind::Knight,
+            PieceKind::Rook,
+            PieceKind::Pawn,
+        ];
+        for (x, &kind) in kinds.iter().enumerate() {
+            inner[0][x] = Some(Piece { kind, colour: PieceColour::Black });
+            inner[7][x + 1] = Some(Piece { kind, colour: PieceColour::White });
+        }
+        Board(inner)
+    }
+
+    /// Messages have no `PartialEq`, so they are compared as JSON.
+    fn assert_round_trips(message: Message) -> Vec<u8> {
+        let encoded = encode(&message);
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&message).unwrap()
+        );
+        encoded
+    }
+
+    #[test]
+    fn state_change_round_trips() {
+        let encoded = assert_round_trips(Message::StateChange(StateChange {
+            board: board(),
+            turn: PieceColour::Black,
+            sequence: 0x0102_0304_0506_0708,
+        }));
+        assert_eq!(encoded.len(), 1 + 32 + 1 + 8);
+    }
+
+    #[test]
+    fn state_delta_round_trips() {
+        let old = StateChange {
+            board: Board([[None; 8]; 8]),
+            turn: PieceColour::White,
+            sequence: 7,
+        };
+        let new = StateChange {
+            board: board(),
+            turn: PieceColour::Black,
+            sequence: 8,
+        };
+        let encoded = assert_round_trips(Message::StateDelta(StateDelta::between(&old, &new)));
+        assert_eq!(encoded[0], STATE_DELTA);
+    }
+
+    #[test]
+    fn large_state_delta_falls_back_to_json() {
+        let changes = (0..65)
+            .map(|square| SquareChange { x: square % 8, y: square / 8 % 8, piece: None })
+            .collect();
+        let encoded = assert_round_trips(Message::StateDelta(StateDelta {
+            base: 1,
+            sequence: 2,
+            turn: PieceColour::White,
+            changes: changes,
+        }));
+        assert_eq!(encoded[0], JSON);
+    }
+
+    #[test]
+    fn vote_call_round_trips() {
+        assert_round_trips(Message::VoteCall(VoteCall {
+            round: 12,
+            position: u64::max_value(),
+            deadline: 1_500_000_000_000,
+        }));
+    }
+
+    #[test]
+    fn vote_round_trips_with_and_without_round() {
+        let action = Action { from: (4, 6), to: (4, 4) };
+        let with = assert_round_trips(Message::Vote(Vote {
+            action: action.clone(),
+            weight: 70_000,
+            round: Some(3),
+        }));
+        let without = assert_round_trips(Message::Vote(Vote {
+            action: action,
+            weight: 0,
+            round: None,
+        }));
+        assert_eq!(with.len(), without.len() + 8);
+    }
+
+    #[test]
+    fn ping_and_pong_round_trip() {
+        assert_round_trips(Message::Ping(Ping { sent: 1_500_000_000_000 }));
+        assert_round_trips(Message::Pong(Pong {
+            sent: 1_500_000_000_000,
+            received: 1_500_000_000_042,
+        }));
+    }
+
+    #[test]
+    fn other_messages_fall_back_to_json() {
+        let encoded = assert_round_trips(Message::Hello(Hello::new(Vec::new(), true)));
+        assert_eq!(encoded[0], JSON);
+        assert_round_trips(Message::Resync);
+        assert_round_trips(Message::Lineage(Lineage {
+            ancestors: vec![Ancestor { id: "root".to_owned(), address: None }],
+        }));
+    }
+
+    #[test]
+    fn truncated_input_is_refused() {
+        let encoded = encode(&Message::StateChange(StateChange {
+            board: board(),
+            turn: PieceColour::White,
+            sequence: 1,
+        }));
+        for length in 0..encoded.len() {
+            match decode(&encoded[..length]) {
+                Err(DecodeError::Truncated) => (),
+                result => panic!("decoded {} bytes as {:?}", length, result),
+            }
+        }
+        let encoded = encode(&Message::Vote(Vote {
+            action: Action { from: (0, 0), to: (0, 1) },
+            weight: 1,
+            round: Some(1),
+        }));
+        match decode(&encoded[..encoded.len() - 1]) {
+            Err(DecodeError::Truncated) => (),
+            result => panic!("decoded {:?}", result),
+        }
+    }
+
+    #[test]
+    fn unknown_tag_is_refused() {
+        match decode(&[0x7f, 0, 0]) {
+            Err(DecodeError::UnknownTag(0x7f)) => (),
+            result => panic!("decoded {:?}", result),
+        }
+    }
+
+    #[test]
+    fn invalid_square_is_refused() {
+        let mut encoded = encode(&Message::StateChange(StateChange {
+            board: board(),
+            turn: PieceColour::White,
+            sequence: 1,
+        }));
+        // kind 7 does not exist
+        encoded[1] = 0x70;
+        match decode(&encoded) {
+            Err(DecodeError::InvalidSquare(0x7)) => (),
+            result => panic!("decoded {:?}", result),
+        }
+        // an empty square can't be black
+        encoded[1] = 0x08;
+        match decode(&encoded) {
+            Err(DecodeError::InvalidSquare(0x8)) => (),
+            result => panic!("decoded {:?}", result),
+        }
+    }
+
+    #[test]
+    fn invalid_colour_is_refused() {
+        let mut encoded = encode(&Message::StateChange(StateChange {
+            board: board(),
+            turn: PieceColour::White,
+            sequence: 1,
+        }));
+        encoded[33] = 2;
+        match decode(&encoded) {
+            Err(DecodeError::InvalidColour(2)) => (),
+            result => panic!("decoded {:?}", result),
+        }
+    }
+}
@@ -1,16 +1,74 @@
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate ring;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+mod clock;
+pub mod codec;
+pub mod token;
+
+pub use clock::{now_millis, ClockOffset, RoundTrip};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Action {
     pub from: (u8, u8),
     pub to: (u8, u8),
 }
 
+/// An end user's vote. Funnels count each ballot with a weight of one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ballot {
+    pub action: Action,
+    /// The vote round this ballot was cast in, from the latest `VoteCall`.
+    pub round: u64,
+}
+
+/// A funnel's aggregate of the votes below it, standing for `weight` end users. Parents only
+/// accept these from child funnels they trust.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Vote {
     pub action: Action,
     pub weight: u32,
+    /// The vote round this vote was cast in. Funnels fill it in from the most recent
+    /// `VoteCall` before sending their aggregate upstream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub round: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct VoteCall {
+    /// Identifies the round, so that late votes are not applied to a later position.
+    pub round: u64,
+    /// The `StateChange::position_hash` of the position being voted on.
+    pub position: u64,
+    /// When votes must be sent upstream, in milliseconds since the UNIX epoch on the
+    /// sender's clock. Each funnel converts it to its own clock before forwarding it.
+    pub deadline: u64,
+}
+
+/// Sent by a child to its parent to measure the clock offset and round trip of their link.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Ping {
+    /// The child's clock when the ping was sent.
+    pub sent: u64,
+}
+
+/// The parent's reply to a `Ping`.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Pong {
+    /// Echoes `Ping::sent`.
+    pub sent: u64,
+    /// The parent's clock when the ping was received.
+    pub received: u64,
+}
+
+impl Pong {
+    pub fn reply(ping: &Ping) -> Pong {
+        Pong {
+            sent: ping.sent,
+            received: now_millis(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
@@ -29,7 +87,7 @@ pub enum PieceColour {
     Black
 }
 
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Piece {
     pub kind: PieceKind,
     pub colour: PieceColour
@@ -42,5 +100,298 @@ pub struct Board(pub [[Option<Piece>; 8]; 8]);
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StateChange {
     pub board: Board,
-    pub turn: PieceColour
+    pub turn: PieceColour,
+    /// Counts up with every state the engine sends, so that deltas can name their base.
+    #[serde(default)]
+    pub sequence: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SquareChange {
+    pub x: u8,
+    pub y: u8,
+    pub piece: Option<Piece>,
+}
+
+/// The squares that differ between two states. Only sent to children that asked for deltas
+/// in their `Hello`; a child that does not hold the `base` state sends `Resync` instead of
+/// applying it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateDelta {
+    pub base: u64,
+    pub sequence: u64,
+    pub turn: PieceColour,
+    pub changes: Vec<SquareChange>,
+}
+
+impl StateDelta {
+    pub fn between(old: &StateChange, new: &StateChange) -> StateDelta {
+        let mut changes = Vec::new();
+        for (y, (old_row, new_row)) in old.board.0.iter().zip(new.board.0.iter()).enumerate() {
+            for (x, (old_square, new_square)) in old_row.iter().zip(new_row.iter()).enumerate() {
+                if old_square != new_square {
+                    changes.push(SquareChange {
+                        x: x as u8,
+                        y: y as u8,
+                        piece: *new_square,
+                    });
+                }
+            }
+        }
+
+        StateDelta {
+            base: old.sequence,
+            sequence: new.sequence,
+            turn: new.turn,
+            changes: changes,
+        }
+    }
+
+    /// Applies the delta to `state`, or returns `None` if `state` is not its base.
+    pub fn apply(&self, state: &StateChange) -> Option<StateChange> {
+        if state.sequence != self.base {
+            return None;
+        }
+
+        let mut board = state.board.clone();
+        for change in &self.changes {
+            if change.x >= 8 || change.y >= 8 {
+                return None;
+            }
+            board.0[change.y as usize][change.x as usize] = change.piece;
+        }
+
+        Some(StateChange {
+            board,
+            turn: self.turn,
+            sequence: self.sequence,
+        })
+    }
+}
+
+impl StateChange {
+    /// A 64-bit FNV-1a hash of the board and the player to move. It is stable across
+    /// builds, so any node in the tree can compare it against a `VoteCall`.
+    pub fn position_hash(&self) -> u64 {
+        fn feed(hash: u64, byte: u8) -> u64 {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        }
+
+        let mut hash = 0xcbf29ce484222325;
+        for row in self.board.0.iter() {
+            for square in row.iter() {
+                hash = match *square {
+                    Some(Piece { kind, colour }) => feed(feed(hash, 1 + kind as u8), colour as u8),
+                    None => feed(hash, 0),
+                };
+            }
+        }
+        feed(hash, self.turn as u8)
+    }
+}
+
+/// A funnel and the funnels below it, reported to its parent once every ping interval.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: String,
+    /// End users connected to this funnel, spectators included.
+    pub clients: usize,
+    pub spectators: usize,
+    /// Smoothed round trip to the funnel's parent in milliseconds, once measured.
+    pub round_trip: Option<u64>,
+    /// When the funnel last sent a vote upstream, in milliseconds since the UNIX epoch on
+    /// its own clock.
+    pub last_vote: Option<u64>,
+    #[serde(default)]
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    /// End users connected anywhere in the subtree.
+    pub fn subtree_clients(&self) -> usize {
+        self.clients + self.children.iter().map(Node::subtree_clients).sum::<usize>()
+    }
+}
+
+/// What a leaf funnel tells the gateway about itself once every ping interval, sent inside a
+/// `LoadReport`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Load {
+    pub id: String,
+    /// The WebSocket URL clients reach the funnel on.
+    pub url: String,
+    /// End users connected, spectators included.
+    pub clients: usize,
+    /// Whether the funnel's parent has welcomed it, so its clients' votes count.
+    pub attached: bool,
+    /// When the funnel sent the report, in epoch milliseconds. The gateway ignores a report
+    /// no newer than the last it took from the funnel, so one can't be replayed.
+    pub sent: u64,
+}
+
+/// A `Load` as a JSON datagram to the gateway, signed with the key the two share.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LoadReport {
+    /// The `Load` as JSON, kept as text so the signature is checked over exactly what was
+    /// signed.
+    pub load: String,
+    /// `token::mac` of `load` with the funnel's key.
+    pub signature: String,
+}
+
+impl LoadReport {
+    pub fn new(load: &Load, key: &[u8]) -> LoadReport {
+        let load = serde_json::to_string(load).unwrap();
+        LoadReport {
+            signature: token::mac(key, &load),
+            load: load,
+        }
+    }
+}
+
+/// A funnel a child could attach to if its parent is lost.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ancestor {
+    pub id: String,
+    /// The `host:port` the funnel takes clients on, if it advertises one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+}
+
+/// Sent by a funnel to its child funnels whenever its own place in the tree changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Lineage {
+    /// The sender first, then its parent and so on up to the funnel beneath the engine.
+    pub ancestors: Vec<Ancestor>,
+}
+
+/// Sent to a child funnel in place of a `Welcome`, for it to prove it holds the key it was
+/// registered with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    pub nonce: String,
+}
+
+/// A child funnel's reply to a `Challenge`, made with `token::answer`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Answer {
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorMessage {
+    pub error: String,
+}
+
+/// Bumped whenever a change to `Message` would be misread by a node built before it.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// How messages are written on a link once the handshake is over. `Hello`, `Welcome` and
+/// anything sent before them are always JSON.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Json,
+    /// The compact format in `codec`, sent in binary WebSocket frames.
+    Binary,
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding::Json
+    }
+}
+
+/// The first message a child sends after connecting to its parent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    /// Encodings the child can use besides JSON, most preferred first.
+    #[serde(default)]
+    pub encodings: Vec<Encoding>,
+    /// Whether the child understands `StateDelta`.
+    #[serde(default)]
+    pub deltas: bool,
+    /// A session token from `token::sign`, for parents that authenticate their children.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Asks to watch without voting.
+    #[serde(default)]
+    pub spectator: bool,
+    /// The id a child funnel is registered under with its parent. The parent answers with a
+    /// `Challenge` instead of a `Welcome`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub funnel: Option<String>,
+    /// A child funnel's subtree as it joins. Later changes follow in `Topology` messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topology: Option<Node>,
+}
+
+/// The parent's reply to a `Hello` it is able to speak to. A parent that cannot sends an
+/// `ErrorMessage` instead and closes the connection.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Welcome {
+    pub version: u32,
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+impl Hello {
+    pub fn new(encodings: Vec<Encoding>, deltas: bool) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            encodings,
+            deltas,
+            token: None,
+            spectator: false,
+            funnel: None,
+            topology: None,
+        }
+    }
+
+    /// Checks the child speaks our version of the protocol, describing why not if it doesn't,
+    /// and picks the child's most preferred encoding out of those we `support`.
+    pub fn accept(&self, support: &[Encoding]) -> Result<Welcome, ErrorMessage> {
+        if self.version == PROTOCOL_VERSION {
+            let encoding = self.encodings
+                .iter()
+                .find(|encoding| support.contains(encoding))
+                .cloned()
+                .unwrap_or(Encoding::Json);
+            Ok(Welcome {
+                version: PROTOCOL_VERSION,
+                encoding,
+            })
+        } else {
+            Err(ErrorMessage {
+                error: format!(
+                    "unsupported protocol version {}, expected {}",
+                    self.version, PROTOCOL_VERSION
+                ),
+            })
+        }
+    }
+}
+
+/// Every message sent between the engine, funnels and clients, in either direction. The
+/// variant is named by a `type` field alongside the message's own fields.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Hello(Hello),
+    Welcome(Welcome),
+    Challenge(Challenge),
+    Answer(Answer),
+    StateChange(StateChange),
+    StateDelta(StateDelta),
+    /// Asks the parent for a full `StateChange` after a gap in the deltas.
+    Resync,
+    VoteCall(VoteCall),
+    Ballot(Ballot),
+    Vote(Vote),
+    Ping(Ping),
+    Pong(Pong),
+    Topology(Node),
+    Lineage(Lineage),
+    Error(ErrorMessage),
 }
@@ -0,0 +1,178 @@
+//! Session tokens: an identity and an expiry time, signed with a secret shared by whoever
+//! issues the tokens and the funnels that check them.
+//!
+//! A token reads `<identity>.<expires>.<signature>`. `expires` is in epoch milliseconds,
+//! and `signature` is the hex HMAC-SHA256 of everything before it.
+//!
+//! Child funnels prove who they are differently, by answering a random challenge from their
+//! parent with a key the two share.
+
+use std::fmt;
+
+use ring::{digest, hmac};
+use ring::rand::{SecureRandom, SystemRandom};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TokenError::Malformed => write!(f, "malformed token"),
+            TokenError::BadSignature => write!(f, "bad token signature"),
+            TokenError::Expired => write!(f, "token expired"),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len() / 2)
+        .map(|i| u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok())
+        .collect()
+}
+
+/// Issues a token for `identity` that is valid until `expires`.
+pub fn sign(secret: &[u8], identity: &str, expires: u64) -> String {
+    let key = hmac::SigningKey::new(&digest::SHA256, secret);
+    let payload = format!("{}.{}", identity, expires);
+    let signature = to_hex(hmac::sign(&key, payload.as_bytes()).as_ref());
+    format!("{}.{}", payload, signature)
+}
+
+/// Checks a token's signature and expiry, returning the identity it was issued for.
+pub fn verify(secret: &[u8], token: &str, now: u64) -> Result<String, TokenError> {
+    let (payload, signature) = match token.rfind('.') {
+        Some(split) => (&token[..split], &token[split + 1..]),
+        None => return Err(TokenError::Malformed),
+    };
+    let (identity, expires) = match payload.rfind('.') {
+        Some(split) => (&payload[..split], &payload[split + 1..]),
+        None => return Err(TokenError::Malformed),
+    };
+    let signature = from_hex(signature).ok_or(TokenError::Malformed)?;
+    let expires: u64 = expires.parse().map_err(|_| TokenError::Malformed)?;
+    if identity.is_empty() {
+        return Err(TokenError::Malformed);
+    }
+
+    let key = hmac::SigningKey::new(&digest::SHA256, secret);
+    hmac::verify_with_own_key(&key, payload.as_bytes(), &signature)
+        .map_err(|_| TokenError::BadSignature)?;
+
+    if expires <= now {
+        return Err(TokenError::Expired);
+    }
+    Ok(identity.to_owned())
+}
+
+/// A fresh random challenge for a child funnel to answer.
+pub fn challenge() -> String {
+    let mut nonce = [0; 32];
+    SystemRandom::new().fill(&mut nonce).expect("System randomness unavailable");
+    to_hex(&nonce)
+}
+
+/// The hex HMAC-SHA256 of `message`, vouching that it came from someone holding `key`.
+pub fn mac(key: &[u8], message: &str) -> String {
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    to_hex(hmac::sign(&key, message.as_bytes()).as_ref())
+}
+
+/// Checks `mac` is the HMAC of `message` under `key`.
+pub fn check_mac(key: &[u8], message: &str, mac: &str) -> bool {
+    let mac = match from_hex(mac) {
+        Some(mac) => mac,
+        None => return false,
+    };
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    hmac::verify_with_own_key(&key, message.as_bytes(), &mac).is_ok()
+}
+
+/// Answers a challenge, proving the child holds `key` without sending it.
+pub fn answer(key: &[u8], challenge: &str) -> String {
+    mac(key, challenge)
+}
+
+/// Checks a child's answer to a challenge against the key it should hold.
+pub fn check_answer(key: &[u8], challenge: &str, answer: &str) -> bool {
+    check_mac(key, challenge, answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &'static [u8] = b"shared secret";
+
+    #[test]
+    fn token_round_trips() {
+        let token = sign(SECRET, "alice", 2000);
+        assert_eq!(verify(SECRET, &token, 1000), Ok("alice".to_owned()));
+    }
+
+    #[test]
+    fn identity_may_contain_dots() {
+        let token = sign(SECRET, "alice.example", 2000);
+        assert_eq!(verify(SECRET, &token, 1000), Ok("alice.example".to_owned()));
+    }
+
+    #[test]
+    fn expired_token_is_refused() {
+        let token = sign(SECRET, "alice", 2000);
+        assert_eq!(verify(SECRET, &token, 2000), Err(TokenError::Expired));
+        assert_eq!(verify(SECRET, &token, 3000), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn forged_signature_is_refused() {
+        let token = sign(SECRET, "alice", 2000);
+        assert_eq!(verify(b"other secret", &token, 1000), Err(TokenError::BadSignature));
+
+        // someone else's signature on a changed identity or expiry
+        let signature = &token[token.rfind('.').unwrap()..];
+        let forged = format!("mallory.2000{}", signature);
+        assert_eq!(verify(SECRET, &forged, 1000), Err(TokenError::BadSignature));
+        let extended = format!("alice.9000{}", signature);
+        assert_eq!(verify(SECRET, &extended, 1000), Err(TokenError::BadSignature));
+    }
+
+    #[test]
+    fn malformed_token_is_refused() {
+        let token = sign(SECRET, "alice", 2000);
+        let signature = &token[token.rfind('.').unwrap() + 1..];
+        let malformed = vec![
+            "".to_owned(),
+            "alice".to_owned(),
+            format!("alice.{}", signature),
+            format!(".2000.{}", signature),
+            "alice.2000.not hex".to_owned(),
+            "alice.2000.abc".to_owned(),
+            format!("alice.soon.{}", signature),
+            format!("alice.-1.{}", signature),
+        ];
+        for token in malformed {
+            assert_eq!(verify(SECRET, &token, 1000), Err(TokenError::Malformed), "{}", token);
+        }
+    }
+
+    #[test]
+    fn answer_checks_against_key() {
+        let challenge = challenge();
+        let answer = answer(b"key", &challenge);
+        assert!(check_answer(b"key", &challenge, &answer));
+        assert!(!check_answer(b"other key", &challenge, &answer));
+        assert!(!check_answer(b"key", "another challenge", &answer));
+        assert!(!check_answer(b"key", &challenge, "zz"));
+    }
+}
@@ -0,0 +1,49 @@
+//! Runs the engine and a root funnel in one process for local development, joined by
+//! channels instead of a socket so there is nothing to start in order.
+//!
+//! Clients connect to the address given, `127.0.0.1:2828` by default.
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate engine;
+extern crate funnel;
+
+use engine::Game;
+use engine::downstream::Downstream;
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let host: SocketAddr = match env::args().nth(1) {
+        Some(host) => host.parse().expect("USAGE: dev [address]"),
+        None => "127.0.0.1:2828".parse().unwrap(),
+    };
+
+    let (to_funnel, from_engine) = mpsc::channel();
+    let (to_engine, from_funnel) = mpsc::channel();
+
+    thread::Builder::new()
+        .name("engine".to_owned())
+        .spawn(move || {
+            let config = engine::Config::in_process();
+            let downstream = Downstream::from_channel(from_funnel, to_funnel);
+            match engine::play(&config, downstream, &mut Game::new()) {
+                Ok(()) => info!("Funnel stopped"),
+                Err(e) => warn!("Engine stopped: {}", e),
+            }
+        })
+        .expect("Could not start engine thread");
+
+    let config = funnel::Config::in_process(host);
+    if let Err(e) = funnel::run_in_process(config, from_engine, to_engine) {
+        error!("Funnel stopped: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,247 @@
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Instant;
+
+use serde_json;
+
+use tungstenite::{self, accept, WebSocket};
+use tungstenite::Message as Frame;
+use tungstenite::HandshakeError::{self, Interrupted};
+use tungstenite::error::Error;
+
+use common::{codec, Encoding, Message};
+
+/// The result of reading a single message from the funnel.
+pub enum Line {
+    Eof,
+    Timeout,
+    TooLong,
+    Text(String),
+    /// A message in `codec` format, only read from links that negotiated it.
+    Binary(Vec<u8>),
+    /// A message handed over whole by a funnel in the same process.
+    Message(Message),
+}
+
+/// A connection to the root funnel. The socket variants carry the same messages; the TCP
+/// variant separates them with newlines while the WebSocket variant uses one frame each, and
+/// may switch to the binary encoding after the handshake. The channel variant passes messages
+/// to a funnel in the same process without encoding them at all.
+pub enum Downstream {
+    TcpSocket {
+        input: BufReader<TcpStream>,
+        output: BufWriter<TcpStream>,
+        /// The start of a line whose read was interrupted by a timeout.
+        pending: Vec<u8>,
+    },
+    WebSocket {
+        socket: WebSocket<TcpStream>,
+        encoding: Encoding,
+    },
+    Channel {
+        input: Receiver<Message>,
+        output: Sender<Message>,
+    },
+}
+
+fn websocket_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::new(ErrorKind::Other, format!("{}", e)),
+    }
+}
+
+/// Reads a line of at most `max_length` bytes, continuing from whatever is already in
+/// `buffer`. Overly long lines are discarded up to and including their newline, so that the
+/// next read starts on a fresh line.
+pub fn read_line<R: BufRead>(
+    input: &mut R,
+    buffer: &mut Vec<u8>,
+    max_length: usize,
+) -> io::Result<Line> {
+    let limit = (max_length + 1 - buffer.len()) as u64;
+    let read = input.by_ref().take(limit).read_until(b'\n', buffer)?;
+
+    if read == 0 {
+        buffer.clear();
+        return Ok(Line::Eof);
+    }
+
+    if buffer.last() != Some(&b'\n') && buffer.len() > max_length {
+        buffer.clear();
+        loop {
+            let (done, used) = {
+                let available = input.fill_buf()?;
+                if available.is_empty() {
+                    break;
+                }
+                match available.iter().position(|&b| b == b'\n') {
+                    Some(position) => (true, position + 1),
+                    None => (false, available.len()),
+                }
+            };
+            input.consume(used);
+            if done {
+                break;
+            }
+        }
+        return Ok(Line::TooLong);
+    }
+
+    let line = String::from_utf8_lossy(buffer).into_owned();
+    buffer.clear();
+    Ok(Line::Text(line))
+}
+
+impl Downstream {
+    pub fn from_tcp(stream: &TcpStream) -> io::Result<Downstream> {
+        Ok(Downstream::TcpSocket {
+            input: BufReader::new(stream.try_clone()?),
+            output: BufWriter::new(stream.try_clone()?),
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn from_websocket(stream: &TcpStream) -> io::Result<Downstream> {
+        let mut websocket = accept(stream.try_clone()?, None);
+        while let Err(Interrupted(in_progress)) = websocket {
+            websocket = in_progress.handshake();
+        }
+        match websocket {
+            Ok(socket) => Ok(Downstream::WebSocket {
+                socket: socket,
+                encoding: Encoding::Json,
+            }),
+            Err(HandshakeError::Failure(e)) => Err(websocket_error(e)),
+            Err(Interrupted(_)) => unreachable!(),
+        }
+    }
+
+    pub fn from_channel(input: Receiver<Message>, output: Sender<Message>) -> Downstream {
+        Downstream::Channel {
+            input: input,
+            output: output,
+        }
+    }
+
+    /// The encodings this connection can switch to after the handshake.
+    pub fn supported_encodings(&self) -> &'static [Encoding] {
+        match *self {
+            Downstream::TcpSocket { .. } | Downstream::Channel { .. } => &[],
+            Downstream::WebSocket { .. } => &[Encoding::Binary],
+        }
+    }
+
+    pub fn set_encoding(&mut self, new_encoding: Encoding) {
+        if let Downstream::WebSocket { ref mut encoding, .. } = *self {
+            *encoding = new_encoding;
+        }
+    }
+
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        match *self {
+            Downstream::TcpSocket { ref mut output, .. } => {
+                #[cfg(debug)]
+                serde_json::to_writer_pretty(&mut *output, message)?;
+                #[cfg(not(debug))]
+                serde_json::to_writer(&mut *output, message)?;
+                writeln!(output, "")?;
+                output.flush()
+            }
+            Downstream::WebSocket {
+                ref mut socket,
+                encoding,
+            } => {
+                let frame = match encoding {
+                    Encoding::Json => Frame::text(serde_json::to_string(message)?),
+                    Encoding::Binary => Frame::binary(codec::encode(message)),
+                };
+                socket.write_message(frame).map_err(websocket_error)
+            }
+            Downstream::Channel { ref output, .. } => output
+                .send(message.clone())
+                .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Funnel has stopped")),
+        }
+    }
+
+    fn stream(&self) -> Option<&TcpStream> {
+        match *self {
+            Downstream::TcpSocket { ref input, .. } => Some(input.get_ref()),
+            Downstream::WebSocket { ref socket, .. } => Some(socket.get_ref()),
+            Downstream::Channel { .. } => None,
+        }
+    }
+
+    /// Reads the next message, rejecting any longer than `max_length` bytes. A closed
+    /// connection is reported as `Line::Eof` and a passed `deadline` as `Line::Timeout`,
+    /// rather than as errors.
+    pub fn receive(&mut self, max_length: usize, deadline: Option<Instant>) -> io::Result<Line> {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline <= now {
+                    return Ok(Line::Timeout);
+                }
+                Some(deadline - now)
+            }
+            None => None,
+        };
+
+        if let Downstream::Channel { ref input, .. } = *self {
+            let received = match timeout {
+                Some(timeout) => input.recv_timeout(timeout),
+                None => input.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            return Ok(match received {
+                Ok(message) => Line::Message(message),
+                Err(RecvTimeoutError::Timeout) => Line::Timeout,
+                Err(RecvTimeoutError::Disconnected) => Line::Eof,
+            });
+        }
+        if let Some(stream) = self.stream() {
+            stream.set_read_timeout(timeout)?;
+        }
+
+        match self.receive_blocking(max_length) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                Ok(Line::Timeout)
+            }
+            result => result,
+        }
+    }
+
+    fn receive_blocking(&mut self, max_length: usize) -> io::Result<Line> {
+        match *self {
+            Downstream::TcpSocket {
+                ref mut input,
+                ref mut pending,
+                ..
+            } => read_line(input, pending, max_length),
+            Downstream::WebSocket {
+                ref mut socket,
+                encoding,
+            } => loop {
+                let message = match socket.read_message() {
+                    Ok(message) => message,
+                    Err(Error::ConnectionClosed(_)) => return Ok(Line::Eof),
+                    Err(e) => return Err(websocket_error(e)),
+                };
+                let (data, binary) = match message {
+                    Frame::Text(text) => (text.into_bytes(), false),
+                    Frame::Binary(data) => (data, encoding == Encoding::Binary),
+                    Frame::Ping(_) | Frame::Pong(_) => continue,
+                };
+                if data.len() > max_length {
+                    return Ok(Line::TooLong);
+                }
+                if binary {
+                    return Ok(Line::Binary(data));
+                }
+                return Ok(Line::Text(String::from_utf8_lossy(&data).into_owned()));
+            },
+            Downstream::Channel { .. } => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! The engine's event log, and replaying it through the rules to check what was logged.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io;
+use std::path::Path;
+
+use itertools::Itertools;
+use serde_json;
+
+use common::{now_millis, Action, PieceColour, StateChange};
+
+use Game;
+
+/// Something that happened to the game. Votes are what the engine was given; everything else
+/// follows from them by the rules, which is what lets a replay check it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A new game began from the starting position.
+    Started { game: u64 },
+    /// The engine picked up a saved game: the round it had reached and the moves played in it
+    /// so far.
+    Resumed {
+        game: u64,
+        round: u64,
+        history: Vec<Action>,
+    },
+    /// The funnel's aggregate vote for a round.
+    Vote {
+        round: u64,
+        action: Action,
+        weight: u32,
+    },
+    /// The vote's action was played, leaving the board at `position`.
+    Accepted {
+        round: u64,
+        action: Action,
+        position: u64,
+    },
+    /// The vote's action broke the rules and was not played.
+    Rejected { round: u64, action: Action },
+    /// `winner` left the other side without a move, ending the game.
+    Checkmate { game: u64, winner: PieceColour },
+}
+
+/// A line of the event log.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Record {
+    /// When the event happened, in milliseconds since the UNIX epoch.
+    pub time: u64,
+    pub event: Event,
+}
+
+/// An append-only file of events, one JSON `Record` per line.
+pub struct EventLog {
+    file: File,
+}
+
+impl EventLog {
+    pub fn open(path: &Path) -> io::Result<EventLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(EventLog { file: file })
+    }
+
+    /// Adds an event in a single write, so a crash can cut off at most the last line.
+    pub fn append(&mut self, event: &Event) -> io::Result<()> {
+        let record = Record {
+            time: now_millis(),
+            event: event.clone(),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)
+    }
+}
+
+/// Rebuilds the game from its event log, checking that the rules give the same outcome for
+/// every logged vote.
+pub struct Replay {
+    game: Game,
+    /// Whether a `Started` or `Resumed` has been seen, so there is a game to compare with.
+    tracking: bool,
+    /// What the rules say the log should hold next, following the last vote.
+    expected: VecDeque<Event>,
+}
+
+impl Default for Replay {
+    fn default() -> Replay {
+        Replay::new()
+    }
+}
+
+impl Replay {
+    pub fn new() -> Replay {
+        Replay {
+            game: Game::new(),
+            tracking: false,
+            expected: VecDeque::new(),
+        }
+    }
+
+    /// Applies the next event from the log. The game moves on even when the event disagrees
+    /// with the rules, and the disagreement is returned.
+    pub fn apply(&mut self, event: &Event) -> Result<(), String> {
+        match *event {
+            Event::Vote {
+                round,
+                ref action,
+                weight,
+            } => {
+                let missing = self.finish();
+                self.game.round = round;
+                let outcome = self.game.vote(action.clone(), weight);
+                self.expected.extend(outcome.into_iter().skip(1));
+                missing
+            }
+            Event::Resumed {
+                game,
+                round,
+                ref history,
+            } => {
+                let agrees = !self.tracking || self.resumes_from(game, round, history);
+                // the save already holds whatever of the last vote's outcome a crash kept out
+                // of the log, so only a save that disagrees with the log is an error
+                self.expected.clear();
+
+                let mut resumed = Game::new();
+                resumed.number = game;
+                resumed.round = round;
+                let mut illegal = None;
+                for action in history {
+                    let mut outcome = Vec::new();
+                    resumed.play(action.clone(), &mut outcome);
+                    if let Some(&Event::Rejected { .. }) = outcome.first() {
+                        illegal = illegal.or_else(|| Some(action.clone()));
+                    }
+                }
+                self.game = resumed;
+                self.tracking = true;
+
+                if let Some(action) = illegal {
+                    return Err(format!("the saved game holds an illegal move {:?}", action));
+                }
+                if !agrees {
+                    return Err("the saved game differs from the one logged so far".to_owned());
+                }
+                Ok(())
+            }
+            ref outcome => match self.expected.pop_front() {
+                Some(ref expected) if expected == outcome => Ok(()),
+                Some(expected) => Err(format!("the rules give {:?}", expected)),
+                None => match *outcome {
+                    Event::Started { game } => {
+                        self.game = Game::new();
+                        self.game.number = game;
+                        self.tracking = true;
+                        Ok(())
+                    }
+                    _ => Err("there is no vote it follows from".to_owned()),
+                },
+            },
+        }
+    }
+
+    /// Whether a saved game follows from the one logged so far. The engine saves each round
+    /// before logging it, so a crash in between leaves the save one round ahead of the log, or
+    /// holding the outcome of a vote whose outcome the log lacks.
+    fn resumes_from(&self, number: u64, round: u64, history: &[Action]) -> bool {
+        let same = self.game.number == number && self.game.history == history;
+        if round == self.game.round {
+            return same;
+        }
+        if round != self.game.round + 1 {
+            return false;
+        }
+        // the round missing from the log played no move, or one that leads to the save
+        same ||
+            (0..8).cartesian_product(0..8)
+                .cartesian_product((0..8).cartesian_product(0..8))
+                .any(|(from, to)| {
+                    let mut game = Game::new();
+                    game.state = self.game.state.clone();
+                    game.number = self.game.number;
+                    game.history = self.game.history.clone();
+                    game.play(Action { from, to }, &mut Vec::new());
+                    game.number == number && game.history == history
+                })
+    }
+
+    /// Checks the log holds everything the rules gave for the last vote.
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.expected.is_empty() {
+            return Ok(());
+        }
+        let missing: Vec<Event> = self.expected.drain(..).collect();
+        Err(format!("the log is missing {:?}", missing))
+    }
+
+    pub fn state(&self) -> &StateChange {
+        &self.game.state
+    }
+
+    pub fn game_number(&self) -> u64 {
+        self.game.number
+    }
+
+    pub fn round(&self) -> u64 {
+        self.game.round
+    }
+
+    /// The moves played so far in the current game.
+    pub fn history(&self) -> &[Action] {
+        &self.game.history
+    }
+}
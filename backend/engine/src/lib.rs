@@ -0,0 +1,1096 @@
+extern crate common;
+extern crate serde_json;
+extern crate toml;
+extern crate itertools;
+extern crate tungstenite;
+
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
+extern crate log;
+
+use std::io::prelude::*;
+use std::io;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use common::{codec, now_millis, Action, Board, ErrorMessage, Message, Node, Piece, PieceColour,
+             PieceKind, Pong, StateChange, Vote, VoteCall};
+
+use itertools::Itertools;
+
+pub mod downstream;
+pub mod events;
+
+use downstream::{read_line, Downstream, Line};
+use events::{Event, EventLog};
+
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    host: SocketAddr,
+    #[serde(default)]
+    websocket: bool,
+    /// Lets a funnel switch to the compact binary encoding, in WebSocket mode only.
+    #[serde(default)]
+    binary_encoding: bool,
+    #[serde(default = "default_max_line_length")]
+    max_line_length: usize,
+    /// How long to wait between a move and the next vote call.
+    #[serde(default = "default_vote_length")]
+    vote_length: Duration,
+    /// How long funnels have to send their votes after a vote call.
+    #[serde(default = "default_vote_timeout")]
+    vote_timeout: Duration,
+    /// Where to keep a JSON view of the funnel tree, rewritten whenever the funnel reports.
+    #[serde(default)]
+    topology_file: Option<PathBuf>,
+    /// Where to save the game after every round, and resume it from on startup.
+    #[serde(default)]
+    state_file: Option<PathBuf>,
+    /// Where to append a line for every vote, move and game, for `replay` to check.
+    #[serde(default)]
+    event_log: Option<PathBuf>,
+}
+
+fn default_max_line_length() -> usize {
+    4096
+}
+
+fn default_vote_length() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_vote_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// How long past a round's deadline to wait for the funnel's vote before playing the round
+/// without a move.
+const VOTE_GRACE: Duration = Duration::from_secs(5);
+
+/// Rounds in a row without a vote after which the funnel is dropped, so that `run` can take
+/// another one, such as the same funnel reattaching.
+const MAX_MISSED_VOTES: u32 = 3;
+
+impl Config {
+    pub fn from_file<P: AsRef<Path> + Clone>(path: P) -> Config {
+        let mut file = File::open(&path).expect("Could not open config file.");
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .expect("Reading config file failed");
+        toml::from_str(&contents).expect("Format file incorrectly formatted")
+    }
+
+    /// Settings for an engine playing a funnel in the same process, which never listens on
+    /// `host`.
+    pub fn in_process() -> Config {
+        Config {
+            host: SocketAddr::from(([127, 0, 0, 1], 0)),
+            websocket: false,
+            binary_encoding: false,
+            max_line_length: default_max_line_length(),
+            vote_length: default_vote_length(),
+            vote_timeout: default_vote_timeout(),
+            topology_file: None,
+            state_file: None,
+            event_log: None,
+        }
+    }
+}
+
+fn init_board() -> Board {
+    let mut inner = [[None; 8]; 8];
+    inner[0][0] = Some(Piece {
+        kind: PieceKind::Rook,
+        colour: PieceColour::Black,
+    });
+    inner[0][1] = Some(Piece {
+        kind: PieceKind::Knight,
+        colour: PieceColour::Black,
+    });
+    inner[0][2] = Some(Piece {
+        kind: PieceKind::Bishop,
+        colour: PieceColour::Black,
+    });
+    inner[0][3] = Some(Piece {
+        kind: PieceKind::Queen,
+        colour: PieceColour::Black,
+    });
+    inner[0][4] = Some(Piece {
+        kind: PieceKind::King,
+        colour: PieceColour::Black,
+    });
+    inner[0][5] = Some(Piece {
+        kind: PieceKind::Bishop,
+        colour: PieceColour::Black,
+    });
+    inner[0][6] = Some(Piece {
+        kind: PieceKind::Knight,
+        colour: PieceColour::Black,
+    });
+    inner[0][7] = Some(Piece {
+        kind: PieceKind::Rook,
+        colour: PieceColour::Black,
+    });
+
+    inner[1][0] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::Black,
+    });
+    inner[1][1] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::Black,
+    });
+    inner[1][2] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::Black,
+    });
+    inner[1][3] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::Black,
+    });
+    inner[1][4] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::Black,
+    });
+    inner[1][5] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::Black,
+    });
+    inner[1][6] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::Black,
+    });
+    inner[1][7] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::Black,
+    });
+
+    inner[6][0] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::White,
+    });
+    inner[6][1] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::White,
+    });
+    inner[6][2] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::White,
+    });
+    inner[6][3] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::White,
+    });
+    inner[6][4] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::White,
+    });
+    inner[6][5] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::White,
+    });
+    inner[6][6] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::White,
+    });
+    inner[6][7] = Some(Piece {
+        kind: PieceKind::Pawn,
+        colour: PieceColour::White,
+    });
+
+    inner[7][0] = Some(Piece {
+        kind: PieceKind::Rook,
+        colour: PieceColour::White,
+    });
+    inner[7][1] = Some(Piece {
+        kind: PieceKind::Knight,
+        colour: PieceColour::White,
+    });
+    inner[7][2] = Some(Piece {
+        kind: PieceKind::Bishop,
+        colour: PieceColour::White,
+    });
+    inner[7][3] = Some(Piece {
+        kind: PieceKind::Queen,
+        colour: PieceColour::White,
+    });
+    inner[7][4] = Some(Piece {
+        kind: PieceKind::King,
+        colour: PieceColour::White,
+    });
+    inner[7][5] = Some(Piece {
+        kind: PieceKind::Bishop,
+        colour: PieceColour::White,
+    });
+    inner[7][6] = Some(Piece {
+        kind: PieceKind::Knight,
+        colour: PieceColour::White,
+    });
+    inner[7][7] = Some(Piece {
+        kind: PieceKind::Rook,
+        colour: PieceColour::White,
+    });
+
+    Board(inner)
+}
+
+fn piece_between<T>(board: &[[Option<T>; 8]; 8], start: (u8, u8), stop: (u8, u8)) -> bool {
+    let (x0, y0) = start;
+    let (x1, y1) = stop;
+    let dx = x1 as i8 - x0 as i8;
+    let dy = y1 as i8 - y0 as i8;
+
+    assert!(dx != 0 || dy != 0);
+
+    if dx.abs() == dy.abs() {
+        let xs: Box<Iterator<Item = u8>> = if dx > 0 {
+            Box::new(x0 + 1..x1)
+        } else {
+            Box::new((x0 + 1..x1).rev())
+        };
+        let ys: Box<Iterator<Item = u8>> = if dy > 0 {
+            Box::new(y0 + 1..y1)
+        } else {
+            Box::new((y0 + 1..y1).rev())
+        };
+        match xs.zip(ys)
+            .find(|&(x, y)| board[y as usize][x as usize].is_some())
+        {
+            Some((x, y)) => {
+                info!("Diagonal collision at ({},{})", x, y);
+                true
+            }
+            _ => false,
+        }
+    } else {
+        (dx == 0 && dy > 0 &&
+            board[y0 as usize + 1..y1 as usize]
+                .iter()
+                .map(|x| &x[x0 as usize])
+                .any(|x| x.is_some())) ||
+            (dx == 0 && dy < 0 &&
+                board[y1 as usize + 1..y0 as usize]
+                    .iter()
+                    .map(|x| &x[x0 as usize])
+                    .any(|x| x.is_some())) ||
+            (dy == 0 && dx > 0 &&
+                board[y0 as usize][x0 as usize + 1..x1 as usize]
+                    .iter()
+                    .any(|x| x.is_some())) ||
+            (dy == 0 && dx < 0 &&
+                board[y0 as usize][x1 as usize + 1..x0 as usize]
+                    .iter()
+                    .any(|x| x.is_some()))
+    }
+}
+
+/// Returns true if a move is possible (excluding check) and false otherwise
+fn process_sans_check_check(
+    board: &[[Option<Piece>; 8]; 8],
+    from: (u8, u8),
+    to: (u8, u8),
+    turn: PieceColour,
+) -> bool {
+    let (x0, y0) = from;
+    let (x1, y1) = to;
+    debug!(
+        "({},{}), ({},{}) -> {:?}",
+        x0,
+        y0,
+        x1,
+        y1,
+        board[y0 as usize][x0 as usize]
+    );
+
+    if let Some(Piece { kind, colour }) = board[y0 as usize][x0 as usize] {
+        if colour != turn {
+            info!(
+                "Move rejected as piece colour ({:?}) != current turn player ({:?})",
+                colour,
+                turn
+            );
+            return false;
+        }
+
+        if x0 == x1 && y0 == y1 {
+            info!(
+                "Move rejected as initial coordinates ({}, {}) == final coordinates ({}, {})",
+                x0,
+                y0,
+                x1,
+                y1
+            );
+            return false;
+        }
+
+        let dx = x1 as i8 - x0 as i8;
+        let dy = y1 as i8 - y0 as i8;
+
+        match kind {
+            PieceKind::King => {
+                if dx.abs() <= 1 && dy.abs() <= 1 {
+                    // no possible way to be moving through things if you only move 1 square
+                } else {
+                    info!("Move rejected as king cannot move more than 1 square");
+                    return false;
+                }
+            }
+            PieceKind::Queen => if dx == 0 || dy == 0 || dx.abs() == dy.abs() {
+                if piece_between(&board, (x0, y0), (x1, y1)) {
+                    info!("Move rejected as there is a piece in front of the queen");
+                    return false;
+                }
+            } else {
+                info!("Move rejected as the queen must move in a straight line");
+                return false;
+            },
+            PieceKind::Bishop => if dx.abs() == dy.abs() {
+                if piece_between(&board, (x0, y0), (x1, y1)) {
+                    info!("Move rejected as there is a piece in front of the bishop");
+                    return false;
+                }
+            } else {
+                info!("Move rejected as the bishop must move in a diagonal line");
+                return false;
+            },
+            PieceKind::Knight => {
+                if dx.abs() == 2 && dy.abs() == 1 {
+                    // horsy can jump over things
+                } else if dx.abs() == 1 && dy.abs() == 2 {
+                    // horsy can jump over things
+                } else {
+                    info!("Move rejected as horsy must move in an L");
+                    return false;
+                }
+            }
+            PieceKind::Rook => if dx == 0 || dy == 0 {
+                if piece_between(&board, (x0, y0), (x1, y1)) {
+                    info!("Move rejected as there is a piece in front of the rook");
+                    return false;
+                }
+            } else {
+                info!("Move rejected as the rook must move in a straight line");
+                return false;
+            },
+            PieceKind::Pawn => if dx == 0 {
+                if (dy == 1 && colour == PieceColour::Black &&
+                    board[y1 as usize][x1 as usize].is_none()) ||
+                    (dy == -1 && colour == PieceColour::White &&
+                        board[y1 as usize][x1 as usize].is_none())
+                {
+                    debug!("Pawn moving 1 square");
+                // pawn just moving forwards, minding its business
+                } else if (dy == 2 && colour == PieceColour::Black && y0 == 1 &&
+                    !piece_between(&board, (x0, y0), (x0, y0 + 3))) ||
+                    (dy == -2 && colour == PieceColour::White && y0 == 6 &&
+                        !piece_between(&board, (x0, y0), (x0, y0 - 3)))
+                {
+                    // pawn just moving forwards - twice
+                    debug!("Pawn moving 2 squares");
+                } else {
+                    info!("Pawns cannot move like that");
+                    return false;
+                }
+            } else if dx.abs() == 1 {
+                debug!("Pawn capturing");
+                match colour {
+                    PieceColour::White => if dy != -1 ||
+                        board[y1 as usize][x1 as usize].is_none() ||
+                        board[y1 as usize][x1 as usize].unwrap().colour != PieceColour::Black
+                    {
+                        info!("Pawn can only move in the X direction if its capturing");
+                        return false;
+                    },
+                    PieceColour::Black => {
+                        if dy != 1 || board[y1 as usize][x1 as usize].is_none() ||
+                            board[y1 as usize][x1 as usize].unwrap().colour != PieceColour::White
+                        {
+                            info!("Pawn can only move in the X direction if its capturing");
+                            return false;
+                        }
+                    }
+                }
+            } else {
+                info!("Pawns cannot move like that");
+                return false;
+            },
+        }
+
+        if board[y1 as usize][x1 as usize]
+            .iter()
+            .any(|x| x.colour == colour)
+        {
+            info!("Cannot take your own piece");
+            return false;
+        }
+    } else {
+        info!(
+            "Cannot move piece at coordinates ({}, {}) as there is no piece there",
+            x0,
+            y0
+        );
+        return false;
+    }
+
+    true
+}
+
+fn process_move(board: &mut Board, turn: PieceColour, action: Action) -> PieceColour {
+    let not_turn = match turn {
+        PieceColour::White => PieceColour::Black,
+        PieceColour::Black => PieceColour::White,
+    };
+    let Action {
+        from: (x0, y0),
+        to: (x1, y1),
+    } = action;
+    let &mut Board(ref mut inner) = board;
+
+    if x0 >= 8 || x1 >= 8 || y0 >= 8 || y1 >= 8 {
+        // das bad
+        return turn;
+    }
+
+    if !process_sans_check_check(&inner, (x0, y0), (x1, y1), turn) {
+        return turn;
+    }
+
+    let king_pos = inner
+        .iter()
+        .enumerate()
+        .filter_map(|(y, &row)| {
+            row.iter()
+                .enumerate()
+                .filter_map(|(x, piece)| {
+                    piece.and_then(|Piece { kind, colour }| {
+                        if kind == PieceKind::King && colour == turn {
+                            Some((x as u8, y as u8))
+                        } else {
+                            None
+                        }
+                    })
+                })
+                .next()
+        })
+        .next()
+        .unwrap();
+
+    if let Some((x, y)) = (0..8).cartesian_product((0..8)).find(|&pos| {
+        process_sans_check_check(&inner, pos, king_pos, not_turn)
+    }) {
+        info!(
+            "Cannot move into check. Vulnerable from piece at ({}, {})",
+            x,
+            y
+        );
+        return turn;
+    }
+
+
+
+    inner[y1 as usize][x1 as usize] = inner[y0 as usize][x0 as usize].take();
+
+    not_turn
+}
+
+fn duration_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Everything the engine carries from one vote round to the next, and saves to `state_file`
+/// after each.
+#[derive(Serialize, Deserialize)]
+pub struct Game {
+    state: StateChange,
+    /// The last vote round called.
+    round: u64,
+    /// Counts games from 1, moving on at each checkmate.
+    number: u64,
+    /// The moves played so far this game, in order.
+    history: Vec<Action>,
+    /// When this game began, in milliseconds since the UNIX epoch.
+    started: u64,
+    /// When the last move was played, in milliseconds since the UNIX epoch.
+    last_move: Option<u64>,
+    #[serde(skip)]
+    log: Option<EventLog>,
+}
+
+impl Default for Game {
+    fn default() -> Game {
+        Game::new()
+    }
+}
+
+impl Game {
+    pub fn new() -> Game {
+        Game {
+            state: StateChange {
+                board: init_board(),
+                turn: PieceColour::White,
+                sequence: 0,
+            },
+            round: 0,
+            number: 1,
+            history: Vec::new(),
+            started: now_millis(),
+            last_move: None,
+            log: None,
+        }
+    }
+
+    /// Picks up the game saved in `state_file`, or starts a new one if nothing has been saved
+    /// yet. A save that can't be read stops the engine rather than being overwritten.
+    pub fn resume(config: &Config) -> Game {
+        let saved = config.state_file.as_ref().and_then(|path| Game::load(path));
+        let event = match saved {
+            Some(ref game) => Event::Resumed {
+                game: game.number,
+                round: game.round,
+                history: game.history.clone(),
+            },
+            None => Event::Started { game: 1 },
+        };
+        let mut game = saved.unwrap_or_default();
+
+        if let Some(ref path) = config.event_log {
+            let log = EventLog::open(path)
+                .unwrap_or_else(|e| panic!("Could not open event log {:?}: {}", path, e));
+            game.log = Some(log);
+        }
+        game.record(&[event]);
+        game
+    }
+
+    fn load(path: &Path) -> Option<Game> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No saved game at {}, starting a new one", path.display());
+                return None;
+            }
+            Err(e) => panic!("Could not open saved game {}: {}", path.display(), e),
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .expect("Reading saved game failed");
+        let game: Game = serde_json::from_str(&contents).expect("Saved game incorrectly formatted");
+        info!(
+            "Resumed game {} at round {} after {} moves",
+            game.number,
+            game.round,
+            game.history.len()
+        );
+        Some(game)
+    }
+
+    fn save(&self, config: &Config) {
+        let path = match config.state_file {
+            Some(ref path) => path,
+            None => return,
+        };
+        let contents = serde_json::to_string_pretty(self).unwrap();
+        if let Err(e) = write_atomically(path, contents.as_bytes()) {
+            warn!("Could not save game to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Plays a round's winning vote. The game is saved before what came of it is logged, so
+    /// a crash in between leaves the log a round behind the save, which a replay allows for.
+    fn play_round(&mut self, config: &Config, action: Action, weight: u32) {
+        let events = self.vote(action, weight);
+        self.save(config);
+        self.record(&events);
+    }
+
+    fn record(&mut self, events: &[Event]) {
+        if let Some(ref mut log) = self.log {
+            for event in events {
+                if let Err(e) = log.append(event) {
+                    warn!("Could not write to the event log: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Plays the winning vote of the current round, returning the vote and everything that
+    /// came of it in order.
+    fn vote(&mut self, action: Action, weight: u32) -> Vec<Event> {
+        let mut events = vec![
+            Event::Vote {
+                round: self.round,
+                action: action.clone(),
+                weight: weight,
+            },
+        ];
+        if weight > 0 {
+            self.play(action, &mut events);
+        }
+        events
+    }
+
+    /// Applies the winning action of a round, starting a new game on checkmate.
+    fn play(&mut self, action: Action, events: &mut Vec<Event>) {
+        let turn = self.state.turn;
+        self.state.turn = process_move(&mut self.state.board, turn, action.clone());
+        if self.state.turn == turn {
+            events.push(Event::Rejected {
+                round: self.round,
+                action: action,
+            });
+            return;
+        }
+        events.push(Event::Accepted {
+            round: self.round,
+            action: action.clone(),
+            position: self.state.position_hash(),
+        });
+        self.history.push(action);
+        self.last_move = Some(now_millis());
+
+        let state = &mut self.state;
+        let mut other_board = state.board.to_owned();
+        if !(0..8)
+            .cartesian_product((0..8))
+            .cartesian_product((0..8).cartesian_product((0..8)))
+            .map(|(from, to)| Action { from, to })
+            .any(|action| {
+                process_move(&mut other_board, state.turn, action) != state.turn
+            }) {
+            info!("Checkmate. Winner {:?}", turn);
+            events.push(Event::Checkmate {
+                game: self.number,
+                winner: turn,
+            });
+            state.board = init_board();
+            state.turn = PieceColour::White;
+            self.number += 1;
+            self.history.clear();
+            self.started = now_millis();
+            self.last_move = None;
+            events.push(Event::Started { game: self.number });
+        }
+    }
+}
+
+/// The funnel tree as written to `topology_file`.
+#[derive(Serialize)]
+struct TopologyView<'a> {
+    /// When the funnel reported, in milliseconds since the UNIX epoch.
+    updated: u64,
+    /// End users connected anywhere in the tree.
+    clients: usize,
+    root: &'a Node,
+}
+
+/// Replaces the file at `path`. The new contents are written alongside, to the same name
+/// with `.tmp` added, and renamed over the old, so neither a reader nor a crash part way
+/// through ever sees half of them.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+    let mut file = File::create(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)?;
+    // make the rename itself survive a crash, where directories can be synced
+    match path.parent() {
+        Some(directory) if directory.is_dir() => {
+            let _ = File::open(directory).and_then(|directory| directory.sync_all());
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Replaces the topology file with the funnel's latest report.
+fn write_topology(config: &Config, root: &Node) {
+    let path = match config.topology_file {
+        Some(ref path) => path,
+        None => return,
+    };
+    let view = TopologyView {
+        updated: now_millis(),
+        clients: root.subtree_clients(),
+        root: root,
+    };
+    let contents = serde_json::to_string_pretty(&view).unwrap();
+    if let Err(e) = write_atomically(path, contents.as_bytes()) {
+        warn!("Could not write topology to {}: {}", path.display(), e);
+    }
+}
+
+fn write_error(downstream: &mut Downstream, error: String) -> io::Result<()> {
+    downstream.send(&Message::Error(ErrorMessage { error }))
+}
+
+enum Received<T> {
+    Message(T),
+    Timeout,
+    Disconnected,
+}
+
+/// Reads the next well formed message from the funnel, answering anything malformed with an
+/// error.
+fn receive_message(
+    config: &Config,
+    downstream: &mut Downstream,
+    deadline: Option<Instant>,
+) -> io::Result<Received<Message>> {
+    loop {
+        let buffer = match downstream.receive(config.max_line_length, deadline)? {
+            Line::Eof => return Ok(Received::Disconnected),
+            Line::Timeout => return Ok(Received::Timeout),
+            Line::TooLong => {
+                error!("Rejected line longer than {} bytes", config.max_line_length);
+                write_error(
+                    downstream,
+                    format!("line exceeds {} bytes", config.max_line_length),
+                )?;
+                continue;
+            }
+            Line::Binary(data) => match codec::decode(&data) {
+                Ok(message) => return Ok(Received::Message(message)),
+                Err(e) => {
+                    error!("Badly formatted binary message: {}: {:?}", e, data);
+                    write_error(downstream, format!("badly formatted message: {}", e))?;
+                    continue;
+                }
+            },
+            Line::Message(message) => return Ok(Received::Message(message)),
+            Line::Text(buffer) => buffer,
+        };
+
+        match serde_json::from_str(buffer.trim_right()) {
+            Ok(message) => return Ok(Received::Message(message)),
+            Err(e) => {
+                error!("Badly formatted message: {}: \"{}\"", e, buffer.trim_right());
+                write_error(downstream, format!("badly formatted message: {}", e))?;
+            }
+        }
+    }
+}
+
+/// Waits for the funnel's `Hello`. Returns false, after telling the funnel why, if it sends
+/// anything else first or speaks a protocol version we don't.
+fn handshake(config: &Config, downstream: &mut Downstream) -> io::Result<bool> {
+    let hello = match receive_message(config, downstream, None)? {
+        Received::Message(Message::Hello(hello)) => hello,
+        Received::Message(message) => {
+            error!("Expected hello from funnel, received {:?}", message);
+            write_error(downstream, "expected hello".to_owned())?;
+            return Ok(false);
+        }
+        _ => return Ok(false),
+    };
+
+    let support = if config.binary_encoding {
+        downstream.supported_encodings()
+    } else {
+        &[]
+    };
+    match hello.accept(support) {
+        Ok(welcome) => {
+            downstream.send(&Message::Welcome(welcome))?;
+            downstream.set_encoding(welcome.encoding);
+            info!("Funnel connected using {:?} encoding", welcome.encoding);
+            if let Some(ref topology) = hello.topology {
+                write_topology(config, topology);
+            }
+            Ok(true)
+        }
+        Err(error) => {
+            error!("Rejected funnel: {}", error.error);
+            downstream.send(&Message::Error(error))?;
+            Ok(false)
+        }
+    }
+}
+
+/// Handles messages from the funnel until a vote for `round` arrives or `deadline` passes.
+/// Pings are answered and topology reports recorded straight away. Votes for any other
+/// round, and messages a funnel should not send, are rejected with an error.
+fn receive_vote(
+    config: &Config,
+    downstream: &mut Downstream,
+    round: Option<u64>,
+    deadline: Option<Instant>,
+) -> io::Result<Received<Vote>> {
+    loop {
+        let vote = match receive_message(config, downstream, deadline)? {
+            Received::Message(Message::Vote(vote)) => vote,
+            Received::Message(Message::Ping(ping)) => {
+                downstream.send(&Message::Pong(Pong::reply(&ping)))?;
+                continue;
+            }
+            Received::Message(Message::Topology(topology)) => {
+                write_topology(config, &topology);
+                continue;
+            }
+            Received::Message(message) => {
+                warn!("Unexpected message from funnel: {:?}", message);
+                write_error(downstream, "unexpected message".to_owned())?;
+                continue;
+            }
+            Received::Timeout => return Ok(Received::Timeout),
+            Received::Disconnected => return Ok(Received::Disconnected),
+        };
+
+        if round.is_none() || vote.round != round {
+            warn!("Rejected vote for round {:?} during round {:?}", vote.round, round);
+            write_error(
+                downstream,
+                format!("vote for round {:?} is stale, current round is {:?}", vote.round, round),
+            )?;
+            continue;
+        }
+
+        return Ok(Received::Message(vote));
+    }
+}
+
+fn serve(config: &Config, stream: &TcpStream, game: &mut Game) -> io::Result<()> {
+    let downstream = if config.websocket {
+        Downstream::from_websocket(stream)?
+    } else {
+        Downstream::from_tcp(stream)?
+    };
+    play(config, downstream, game)
+}
+
+/// Plays the game with a single connected funnel until it disconnects or stops voting.
+pub fn play(config: &Config, mut downstream: Downstream, game: &mut Game) -> io::Result<()> {
+    if !handshake(config, &mut downstream)? {
+        return Ok(());
+    }
+
+    let mut missed = 0;
+    loop {
+        game.state.sequence += 1;
+        downstream.send(&Message::StateChange(game.state.clone()))?;
+
+        let pause = Instant::now() + config.vote_length;
+        if let Received::Disconnected = receive_vote(config, &mut downstream, None, Some(pause))? {
+            return Ok(());
+        }
+
+        game.round += 1;
+        info!("Starting vote round {}", game.round);
+        downstream.send(&Message::VoteCall(VoteCall {
+            round: game.round,
+            position: game.state.position_hash(),
+            deadline: now_millis() + duration_millis(config.vote_timeout),
+        }))?;
+
+        let deadline = Instant::now() + config.vote_timeout + VOTE_GRACE;
+        let Vote { action, weight, .. } =
+            match receive_vote(config, &mut downstream, Some(game.round), Some(deadline))? {
+                Received::Message(vote) => {
+                    missed = 0;
+                    vote
+                }
+                Received::Timeout => {
+                    warn!("No vote for round {} in time, playing it without a move", game.round);
+                    missed += 1;
+                    Vote {
+                        action: Action { from: (0, 0), to: (0, 0) },
+                        weight: 0,
+                        round: Some(game.round),
+                    }
+                }
+                Received::Disconnected => return Ok(()),
+            };
+        debug!("New move: {:?} (weight = {})", action, weight);
+        game.play_round(config, action, weight);
+
+        if missed >= MAX_MISSED_VOTES {
+            warn!("Funnel missed {} votes in a row, dropping it", missed);
+            return Ok(());
+        }
+    }
+}
+
+fn write_line<W: Write>(output: &mut W, message: &Message) -> io::Result<()> {
+    serde_json::to_writer(&mut *output, message)?;
+    writeln!(output, "")?;
+    output.flush()
+}
+
+/// Plays a round for every `Vote` line read from `input`, without waiting between rounds.
+/// The state is written to `output` as a `StateChange` line at the start and after each
+/// vote, so every vote is answered by exactly one line. Anything other than a vote is
+/// answered with an `Error` line instead. Returns once `input` ends.
+pub fn play_lines<R: BufRead, W: Write>(mut input: R, mut output: W, game: &mut Game)
+    -> io::Result<()> {
+    let max_line_length = default_max_line_length();
+    let mut buffer = Vec::new();
+
+    game.state.sequence += 1;
+    write_line(&mut output, &Message::StateChange(game.state.clone()))?;
+
+    loop {
+        let line = match read_line(&mut input, &mut buffer, max_line_length)? {
+            Line::Eof => return Ok(()),
+            Line::Text(line) => line,
+            Line::Timeout | Line::Binary(_) | Line::Message(_) => unreachable!(),
+            Line::TooLong => {
+                let error = format!("line exceeds {} bytes", max_line_length);
+                write_line(&mut output, &Message::Error(ErrorMessage { error }))?;
+                continue;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let Vote { action, weight, .. } = match serde_json::from_str(line.trim_right()) {
+            Ok(Message::Vote(vote)) => vote,
+            Ok(message) => {
+                warn!("Expected a vote, received {:?}", message);
+                let error = "expected a vote".to_owned();
+                write_line(&mut output, &Message::Error(ErrorMessage { error }))?;
+                continue;
+            }
+            Err(e) => {
+                error!("Badly formatted message: {}: \"{}\"", e, line.trim_right());
+                let error = format!("badly formatted message: {}", e);
+                write_line(&mut output, &Message::Error(ErrorMessage { error }))?;
+                continue;
+            }
+        };
+
+        game.round += 1;
+        debug!("New move: {:?} (weight = {})", action, weight);
+        let events = game.vote(action, weight);
+        game.record(&events);
+        game.state.sequence += 1;
+        write_line(&mut output, &Message::StateChange(game.state.clone()))?;
+    }
+}
+
+/// Listens on `host` and plays with each funnel that connects, one at a time.
+pub fn run(config: Config) {
+    let listener = TcpListener::bind(config.host).expect("Could not bind to host");
+    info!(
+        "Listening on {} ({})",
+        config.host,
+        if config.websocket { "WebSocket" } else { "TCP" }
+    );
+
+    let mut game = Game::resume(&config);
+    loop {
+        let (stream, address) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Accepting connection failed: {}", e);
+                continue;
+            }
+        };
+        info!("Connection established: {}", address);
+
+        match serve(&config, &stream, &mut game) {
+            Ok(()) => info!("Funnel disconnected: {}", address),
+            Err(e) => warn!("Connection to {} failed: {}", address, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("engine-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn saving_to(path: PathBuf) -> Config {
+        let mut config = Config::in_process();
+        config.state_file = Some(path);
+        config
+    }
+
+    fn action(from: (u8, u8), to: (u8, u8)) -> Action {
+        Action { from, to }
+    }
+
+    fn assert_same(resumed: &Game, game: &Game) {
+        assert_eq!(
+            serde_json::to_string(&resumed.state).unwrap(),
+            serde_json::to_string(&game.state).unwrap()
+        );
+        assert_eq!(resumed.round, game.round);
+        assert_eq!(resumed.number, game.number);
+        assert_eq!(resumed.history, game.history);
+    }
+
+    #[test]
+    fn resumes_the_saved_game() {
+        let config = saving_to(scratch_dir("resume").join("game.json"));
+        let mut game = Game::resume(&config);
+        let moves = [((4, 6), (4, 4)), ((4, 1), (4, 3)), ((0, 0), (0, 5)), ((6, 7), (5, 5))];
+        for &(from, to) in &moves {
+            game.round += 1;
+            game.play_round(&config, action(from, to), 1);
+        }
+        assert_eq!(game.history.len(), 3);
+
+        assert_same(&Game::resume(&config), &game);
+    }
+
+    #[test]
+    fn resumes_after_checkmate() {
+        let config = saving_to(scratch_dir("resume-mate").join("game.json"));
+        let mut game = Game::resume(&config);
+        let moves = [((5, 6), (5, 5)), ((4, 1), (4, 3)), ((6, 6), (6, 4)), ((3, 0), (7, 4))];
+        for &(from, to) in &moves {
+            game.round += 1;
+            game.play_round(&config, action(from, to), 1);
+        }
+        assert_eq!(game.number, 2);
+
+        assert_same(&Game::resume(&config), &game);
+    }
+
+    #[test]
+    fn starts_a_new_game_without_a_save() {
+        let config = saving_to(scratch_dir("fresh").join("game.json"));
+        let game = Game::resume(&config);
+        assert_same(&game, &Game::new());
+        assert!(!config.state_file.as_ref().unwrap().exists());
+    }
+
+    #[test]
+    #[should_panic(expected = "Saved game incorrectly formatted")]
+    fn refuses_a_corrupt_save() {
+        let path = scratch_dir("corrupt").join("game.json");
+        File::create(&path).unwrap().write_all(b"{\"state\": {\"board\"").unwrap();
+        Game::resume(&saving_to(path));
+    }
+
+    #[test]
+    fn keeps_a_save_named_like_a_temporary_file() {
+        let dir = scratch_dir("tmp-name");
+        let config = saving_to(dir.join("game.tmp"));
+        let mut game = Game::resume(&config);
+        game.round += 1;
+        game.play_round(&config, action((4, 6), (4, 4)), 1);
+
+        assert_same(&Game::resume(&config), &game);
+        assert!(!dir.join("game.tmp.tmp").exists());
+    }
+
+    #[test]
+    fn vote_times_default_when_left_out() {
+        let config: Config = toml::from_str("host = \"127.0.0.1:3001\"").unwrap();
+        assert_eq!(config.vote_length, Duration::from_secs(5));
+        assert_eq!(config.vote_timeout, Duration::from_secs(5));
+    }
+}
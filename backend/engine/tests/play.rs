@@ -0,0 +1,62 @@
+extern crate common;
+extern crate engine;
+extern crate serde_json;
+
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::sync::mpsc;
+use std::thread;
+
+use common::{Hello, Message};
+use engine::{Config, Game};
+use engine::downstream::Downstream;
+use engine::events::{Event, Record};
+
+#[test]
+fn drops_a_funnel_that_stops_voting() {
+    let dir = std::env::temp_dir().join(format!("engine-silent-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("engine.toml");
+    let mut file = File::create(&path).unwrap();
+    write!(
+        file,
+        "host = \"127.0.0.1:0\"\n\
+         vote_length = {{ secs = 0, nanos = 0 }}\n\
+         vote_timeout = {{ secs = 0, nanos = 0 }}\n\
+         event_log = {:?}\n",
+        dir.join("events.jsonl")
+    ).unwrap();
+    let config = Config::from_file(&path);
+    let mut game = Game::resume(&config);
+
+    let (to_engine, from_funnel) = mpsc::channel();
+    let (to_funnel, from_engine) = mpsc::channel();
+    let engine = thread::spawn(move || {
+        let downstream = Downstream::from_channel(from_funnel, to_funnel);
+        engine::play(&config, downstream, &mut game).unwrap();
+    });
+
+    // the funnel says hello and then never votes
+    to_engine.send(Message::Hello(Hello::new(Vec::new(), false))).unwrap();
+    let mut vote_calls = 0;
+    while let Ok(message) = from_engine.recv() {
+        if let Message::VoteCall(_) = message {
+            vote_calls += 1;
+        }
+    }
+    engine.join().unwrap();
+    assert_eq!(vote_calls, 3);
+
+    // each round was played without a move
+    let mut log = String::new();
+    File::open(dir.join("events.jsonl")).unwrap().read_to_string(&mut log).unwrap();
+    let votes: Vec<_> = log
+        .lines()
+        .filter_map(|line| match serde_json::from_str::<Record>(line).unwrap().event {
+            Event::Vote { round, weight, .. } => Some((round, weight)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(votes, vec![(1, 0), (2, 0), (3, 0)]);
+}
@@ -0,0 +1,230 @@
+extern crate common;
+extern crate engine;
+extern crate serde_json;
+
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+
+use common::{Action, Hello, Message, Vote};
+use engine::{Config, Game};
+use engine::downstream::Downstream;
+use engine::events::{Event, Record, Replay};
+
+fn vote(round: u64, from: (u8, u8), to: (u8, u8), weight: u32) -> Event {
+    Event::Vote {
+        round: round,
+        action: Action { from, to },
+        weight: weight,
+    }
+}
+
+#[test]
+fn accepts_what_the_rules_give() {
+    let mut replay = Replay::new();
+    replay.apply(&Event::Started { game: 1 }).unwrap();
+    replay.apply(&vote(1, (4, 6), (4, 4), 3)).unwrap();
+    let accepted = Event::Accepted {
+        round: 1,
+        action: Action { from: (4, 6), to: (4, 4) },
+        position: replay.state().position_hash(),
+    };
+    replay.apply(&accepted).unwrap();
+    replay.apply(&vote(2, (0, 0), (0, 0), 0)).unwrap();
+    replay.finish().unwrap();
+    assert_eq!(replay.history().len(), 1);
+    assert_eq!(replay.round(), 2);
+}
+
+#[test]
+fn flags_outcomes_the_rules_disagree_with() {
+    let mut replay = Replay::new();
+    replay.apply(&Event::Started { game: 1 }).unwrap();
+    replay.apply(&vote(1, (4, 6), (4, 2), 3)).unwrap();
+    let accepted = Event::Accepted {
+        round: 1,
+        action: Action { from: (4, 6), to: (4, 2) },
+        position: 0,
+    };
+    assert!(replay.apply(&accepted).is_err());
+    assert!(replay.history().is_empty());
+}
+
+#[test]
+fn flags_missing_outcomes() {
+    let mut replay = Replay::new();
+    replay.apply(&Event::Started { game: 1 }).unwrap();
+    replay.apply(&vote(1, (4, 6), (4, 4), 3)).unwrap();
+    assert!(replay.apply(&vote(2, (4, 1), (4, 3), 3)).is_err());
+    assert!(replay.finish().is_err());
+}
+
+#[test]
+fn accepts_a_resumed_game_holding_an_unlogged_outcome() {
+    let mut replay = Replay::new();
+    replay.apply(&Event::Started { game: 1 }).unwrap();
+    replay.apply(&vote(1, (4, 6), (4, 4), 3)).unwrap();
+    let resumed = Event::Resumed {
+        game: 1,
+        round: 1,
+        history: vec![Action { from: (4, 6), to: (4, 4) }],
+    };
+    replay.apply(&resumed).unwrap();
+    replay.finish().unwrap();
+    assert_eq!(replay.history().len(), 1);
+}
+
+#[test]
+fn flags_a_resumed_game_that_differs_from_the_log() {
+    let mut replay = Replay::new();
+    replay.apply(&Event::Started { game: 1 }).unwrap();
+    let resumed = Event::Resumed {
+        game: 1,
+        round: 4,
+        history: vec![Action { from: (4, 6), to: (4, 4) }],
+    };
+    assert!(replay.apply(&resumed).is_err());
+    assert_eq!(replay.history().len(), 1);
+}
+
+#[test]
+fn flags_a_save_one_round_ahead_that_no_move_explains() {
+    let mut replay = Replay::new();
+    replay.apply(&Event::Started { game: 1 }).unwrap();
+    let resumed = Event::Resumed {
+        game: 1,
+        round: 1,
+        history: vec![
+            Action { from: (4, 6), to: (4, 4) },
+            Action { from: (4, 1), to: (4, 3) },
+        ],
+    };
+    assert!(replay.apply(&resumed).is_err());
+}
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("engine-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn config(dir: &PathBuf) -> Config {
+    let path = dir.join("engine.toml");
+    let mut file = File::create(&path).unwrap();
+    write!(
+        file,
+        "host = \"127.0.0.1:0\"\n\
+         vote_length = {{ secs = 0, nanos = 0 }}\n\
+         vote_timeout = {{ secs = 5, nanos = 0 }}\n\
+         state_file = {:?}\n\
+         event_log = {:?}\n",
+        dir.join("game.json"),
+        dir.join("events.jsonl")
+    ).unwrap();
+    Config::from_file(&path)
+}
+
+/// Resumes the engine from `dir` and plays `moves` as the winning votes of a funnel, one
+/// round each, before the funnel goes away.
+fn session(dir: &PathBuf, moves: &[((u8, u8), (u8, u8))]) {
+    let config = config(dir);
+    let mut game = Game::resume(&config);
+
+    let (to_engine, from_funnel) = mpsc::channel();
+    let (to_funnel, from_engine) = mpsc::channel();
+    let engine = thread::spawn(move || {
+        let downstream = Downstream::from_channel(from_funnel, to_funnel);
+        let _ = engine::play(&config, downstream, &mut game);
+    });
+
+    to_engine.send(Message::Hello(Hello::new(Vec::new(), false))).unwrap();
+    for &(from, to) in moves {
+        let round = loop {
+            match from_engine.recv().unwrap() {
+                Message::VoteCall(vote_call) => break vote_call.round,
+                _ => (),
+            }
+        };
+        let vote = Vote {
+            action: Action { from, to },
+            weight: 1,
+            round: Some(round),
+        };
+        to_engine.send(Message::Vote(vote)).unwrap();
+    }
+    // wait for the last round to be played before going away
+    loop {
+        if let Message::VoteCall(_) = from_engine.recv().unwrap() {
+            break;
+        }
+    }
+    drop(to_engine);
+    drop(from_engine);
+    engine.join().unwrap();
+}
+
+fn replay_log(dir: &PathBuf) -> Replay {
+    let mut log = String::new();
+    File::open(dir.join("events.jsonl")).unwrap().read_to_string(&mut log).unwrap();
+    let mut replay = Replay::new();
+    for line in log.lines() {
+        let record: Record = serde_json::from_str(line).unwrap();
+        if let Err(e) = replay.apply(&record.event) {
+            panic!("{:?} disagrees: {}", record.event, e);
+        }
+    }
+    replay.finish().unwrap();
+    replay
+}
+
+#[test]
+fn engine_log_replays_cleanly() {
+    let dir = scratch_dir("replay");
+    // fool's mate, with an illegal move along the way
+    session(&dir, &[((5, 6), (5, 5)), ((4, 1), (4, 3)), ((0, 7), (0, 2))]);
+    session(&dir, &[((6, 6), (6, 4)), ((3, 0), (7, 4)), ((4, 6), (4, 4))]);
+
+    let replay = replay_log(&dir);
+    assert_eq!(replay.game_number(), 2);
+    assert_eq!(replay.history().len(), 1);
+    assert_eq!(replay.round(), 6);
+}
+
+#[test]
+fn save_ahead_of_the_log_replays_cleanly() {
+    let dir = scratch_dir("lost-log");
+    session(&dir, &[((4, 6), (4, 4)), ((4, 1), (4, 3))]);
+
+    // as if the engine crashed after saving the last round but before logging it
+    let path = dir.join("events.jsonl");
+    let mut log = String::new();
+    File::open(&path).unwrap().read_to_string(&mut log).unwrap();
+    let kept: Vec<&str> = log.lines().take(log.lines().count() - 2).collect();
+    assert!(log.lines().last().unwrap().contains("accepted"));
+    File::create(&path).unwrap().write_all((kept.join("\n") + "\n").as_bytes()).unwrap();
+
+    session(&dir, &[((6, 7), (5, 5))]);
+    let replay = replay_log(&dir);
+    assert_eq!(replay.history().len(), 3);
+}
+
+#[test]
+fn vote_logged_without_its_outcome_replays_cleanly() {
+    let dir = scratch_dir("lost-outcome");
+    session(&dir, &[((4, 6), (4, 4)), ((4, 1), (4, 3))]);
+
+    // as if the engine crashed after logging the last vote but before logging its outcome
+    let path = dir.join("events.jsonl");
+    let mut log = String::new();
+    File::open(&path).unwrap().read_to_string(&mut log).unwrap();
+    let kept: Vec<&str> = log.lines().take(log.lines().count() - 1).collect();
+    assert!(log.lines().last().unwrap().contains("accepted"));
+    File::create(&path).unwrap().write_all((kept.join("\n") + "\n").as_bytes()).unwrap();
+
+    session(&dir, &[((6, 7), (5, 5))]);
+    let replay = replay_log(&dir);
+    assert_eq!(replay.history().len(), 3);
+}
@@ -0,0 +1,59 @@
+extern crate common;
+extern crate engine;
+extern crate serde_json;
+
+use common::{Message, PieceColour, StateChange};
+use engine::Game;
+
+fn play(input: &str) -> Vec<Message> {
+    let mut output = Vec::new();
+    engine::play_lines(input.as_bytes(), &mut output, &mut Game::new()).unwrap();
+    String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn state(message: &Message) -> &StateChange {
+    match *message {
+        Message::StateChange(ref state) => state,
+        ref message => panic!("expected a state change, got {:?}", message),
+    }
+}
+
+#[test]
+fn answers_each_vote_with_the_new_state() {
+    let output = play(
+        "{\"type\":\"vote\",\"action\":{\"from\":[4,6],\"to\":[4,4]},\"weight\":3}\n\
+         {\"type\":\"vote\",\"action\":{\"from\":[4,1],\"to\":[4,3]},\"weight\":1}\n",
+    );
+    assert_eq!(output.len(), 3);
+    assert_eq!(state(&output[0]).turn, PieceColour::White);
+    assert_eq!(state(&output[1]).turn, PieceColour::Black);
+    assert_eq!(state(&output[2]).turn, PieceColour::White);
+    assert_eq!(state(&output[2]).sequence, 3);
+}
+
+#[test]
+fn skips_illegal_and_empty_votes() {
+    let output = play(
+        "{\"type\":\"vote\",\"action\":{\"from\":[4,6],\"to\":[4,2]},\"weight\":3}\n\
+         {\"type\":\"vote\",\"action\":{\"from\":[4,6],\"to\":[4,4]},\"weight\":0}\n",
+    );
+    assert_eq!(output.len(), 3);
+    assert_eq!(state(&output[2]).turn, PieceColour::White);
+    assert_eq!(state(&output[2]).board.0, state(&output[0]).board.0);
+}
+
+#[test]
+fn answers_anything_else_with_an_error() {
+    let output = play("{\"type\":\"resync\"}\nnot json\n");
+    assert_eq!(output.len(), 3);
+    for message in &output[1..] {
+        match *message {
+            Message::Error(_) => (),
+            ref message => panic!("expected an error, got {:?}", message),
+        }
+    }
+}
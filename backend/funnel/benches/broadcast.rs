@@ -0,0 +1,99 @@
+//! Compares broadcasting a state to many clients by handing each client's `WebSocket` its own
+//! copy of the message against framing it once and queueing the shared frame.
+//!
+//! Run with `cargo bench -p funnel`. Sockets write into a sink, so this measures only the
+//! work done in the funnel per broadcast.
+
+extern crate funnel;
+extern crate tungstenite;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use tungstenite::{Message, WebSocket};
+use tungstenite::protocol::Role;
+
+use funnel::broadcast::{self, WriteQueue};
+
+const CLIENTS: usize = 50_000;
+const ROUNDS: u32 = 10;
+
+/// A socket that accepts everything written to it and never has anything to read.
+struct Sink;
+
+impl Read for Sink {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(ErrorKind::WouldBlock, "Nothing to read"))
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Roughly the size of a `StateChange` in JSON.
+fn payload() -> String {
+    let mut text = String::from("{\"type\":\"state_change\",\"board\":[");
+    while text.len() < 1600 {
+        text.push_str("{\"kind\":\"Pawn\",\"colour\":\"White\"},");
+    }
+    text.push_str("null]}");
+    text
+}
+
+fn copied(payload: &str, sockets: &mut [WebSocket<Sink>]) {
+    for socket in sockets {
+        socket.write_message(Message::text(payload.to_owned())).unwrap();
+        socket.write_pending().unwrap();
+    }
+}
+
+fn shared(payload: &str, queues: &mut [WriteQueue]) {
+    let frame = broadcast::frame(&Message::text(payload.to_owned()));
+    for queue in queues {
+        queue.push(frame.clone());
+        queue.flush(&mut Sink).unwrap();
+    }
+}
+
+fn time<F: FnMut()>(name: &str, mut broadcast: F) -> Duration {
+    broadcast();
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        broadcast();
+    }
+    let per_round = start.elapsed() / ROUNDS;
+
+    let nanos = per_round.as_secs() * 1_000_000_000 + per_round.subsec_nanos() as u64;
+    println!(
+        "{:>8}: {:>10} ns per broadcast, {:>6} ns per client",
+        name,
+        nanos,
+        nanos / CLIENTS as u64
+    );
+    per_round
+}
+
+fn main() {
+    let payload = payload();
+    println!("Broadcasting {} bytes to {} clients", payload.len(), CLIENTS);
+
+    let mut sockets: Vec<_> = (0..CLIENTS)
+        .map(|_| WebSocket::from_raw_socket(Sink, Role::Server))
+        .collect();
+    let copied = time("copied", || copied(&payload, &mut sockets));
+
+    let mut queues: Vec<_> = (0..CLIENTS).map(|_| WriteQueue::new()).collect();
+    let shared = time("shared", || shared(&payload, &mut queues));
+
+    let ratio = (copied.as_secs() as f64 * 1e9 + copied.subsec_nanos() as f64)
+        / (shared.as_secs() as f64 * 1e9 + shared.subsec_nanos() as f64);
+    println!("Sharing frames is {:.1}x faster", ratio);
+}
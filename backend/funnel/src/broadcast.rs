@@ -0,0 +1,115 @@
+//! Sending the same WebSocket message to many clients.
+//!
+//! Frames from a server are never masked, so a message encodes to the same bytes for every
+//! client. `frame` encodes it once and each client's `WriteQueue` holds a reference to the
+//! shared buffer rather than a copy, on whichever worker thread the client lives.
+
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Write};
+use std::sync::Arc;
+
+use tungstenite::Message;
+
+/// An encoded WebSocket frame, shared between the clients it is queued for.
+pub type Frame = Arc<Vec<u8>>;
+
+/// Encodes `message` as a single unmasked frame.
+pub fn frame(message: &Message) -> Frame {
+    let (opcode, payload): (u8, &[u8]) = match *message {
+        Message::Text(ref text) => (0x1, text.as_bytes()),
+        Message::Binary(ref data) => (0x2, data),
+        Message::Ping(ref data) => (0x9, data),
+        Message::Pong(ref data) => (0xa, data),
+    };
+
+    let mut buffer = Vec::with_capacity(payload.len() + 10);
+    buffer.push(0x80 | opcode);
+    if payload.len() < 126 {
+        buffer.push(payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        buffer.push(126);
+        buffer.push((payload.len() >> 8) as u8);
+        buffer.push(payload.len() as u8);
+    } else {
+        buffer.push(127);
+        for shift in (0..8).rev() {
+            buffer.push((payload.len() as u64 >> (shift * 8)) as u8);
+        }
+    }
+    buffer.extend_from_slice(payload);
+
+    Arc::new(buffer)
+}
+
+/// Frames waiting to be written to one client.
+pub struct WriteQueue {
+    frames: VecDeque<Frame>,
+    /// How much of the front frame has already been written.
+    written: usize,
+    /// Bytes still to be written, across every frame.
+    bytes: usize,
+}
+
+impl Default for WriteQueue {
+    fn default() -> WriteQueue {
+        WriteQueue::new()
+    }
+}
+
+impl WriteQueue {
+    pub fn new() -> WriteQueue {
+        WriteQueue {
+            frames: VecDeque::new(),
+            written: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn push(&mut self, frame: Frame) {
+        self.bytes += frame.len();
+        self.frames.push_back(frame);
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// How many bytes are still to be written.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Whether a frame has been partly written. Nothing else may be written to the socket
+    /// until it is finished.
+    pub fn in_frame(&self) -> bool {
+        self.written > 0
+    }
+
+    /// Writes as much as the socket accepts. Frames left over are sent by a later call once
+    /// the socket is writable again.
+    pub fn flush<W: Write>(&mut self, socket: &mut W) -> io::Result<()> {
+        while let Some(frame) = self.frames.front().cloned() {
+            match socket.write(&frame[self.written..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "Socket closed")),
+                Ok(length) => {
+                    self.written += length;
+                    self.bytes -= length;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+
+            if self.written == frame.len() {
+                self.frames.pop_front();
+                self.written = 0;
+            }
+        }
+
+        Ok(())
+    }
+}
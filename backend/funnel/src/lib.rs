@@ -0,0 +1,1323 @@
+extern crate mio;
+extern crate net2;
+extern crate rustls;
+extern crate slab;
+extern crate tungstenite;
+extern crate url;
+extern crate webpki;
+extern crate webpki_roots;
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate serde_json;
+extern crate toml;
+
+#[macro_use]
+extern crate log;
+
+extern crate rand;
+
+extern crate common;
+
+pub mod broadcast;
+pub mod listener;
+pub mod tls;
+
+mod limits;
+mod mailbox;
+mod registry;
+mod worker;
+
+use mio::*;
+use mio::net::{TcpListener,TcpStream};
+
+use tls::{ClientStream, Stream, TlsStream};
+
+use tungstenite::{WebSocket, Message, handshake};
+use tungstenite::handshake::MidHandshake;
+use tungstenite::handshake::client::ClientHandshake;
+use tungstenite::HandshakeError::{self, Interrupted};
+use tungstenite::util::NonBlockingError;
+
+use common::{Vote, StateChange, StateDelta, Action, Ping, Hello, Answer, Node, Ancestor,
+             Lineage, Load, LoadReport, Message as Protocol, Encoding, ClockOffset, RoundTrip,
+             now_millis, codec, token};
+
+use limits::{Ballots, DroppedVotes};
+use mailbox::{mailbox, Inbox, Mailbox};
+use registry::Children;
+use worker::{ClientMetrics, Command, Frames, Report, Tally, Worker};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::io::prelude::*;
+use std::io::{self, BufReader, ErrorKind};
+use std::time::{self, Duration};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Config {
+    /// What this funnel is called in the topology, when it has no `upstream_auth` id. The
+    /// `host` address is used when left out.
+    #[serde(default)]
+    id: Option<String>,
+    host: SocketAddr,
+    upstream: SocketAddr,
+    upstream_is_websocket: bool,
+    /// A second address to listen on, whose clients all watch without voting.
+    #[serde(default)]
+    spectator_host: Option<SocketAddr>,
+    /// How much earlier than the parent's deadline to close local voting. Measured from the
+    /// upstream round trip when left out.
+    #[serde(default)]
+    timeout_change: Option<Duration>,
+    /// Time allowed for tallying votes, added to the measured upstream latency.
+    #[serde(default = "default_processing_time")]
+    processing_time: Duration,
+    #[serde(default = "default_ping_interval")]
+    ping_interval: Duration,
+    /// Use the compact binary encoding with WebSocket peers that support it. Browsers
+    /// always get JSON.
+    #[serde(default)]
+    binary_encoding: bool,
+    /// Let other funnel processes listen on the same `host`, each with its own upstream
+    /// link. Unix only.
+    #[serde(default)]
+    reuse_port: bool,
+    /// Serve clients over TLS (`wss://`).
+    #[serde(default)]
+    tls: Option<TlsConfig>,
+    /// Connect to upstream over TLS.
+    #[serde(default)]
+    upstream_tls: Option<UpstreamTlsConfig>,
+    /// How this funnel proves itself to an upstream funnel it is registered with.
+    #[serde(default)]
+    upstream_auth: Option<ChildConfig>,
+    /// The child funnels allowed to send weighted votes, each with the key it proves itself
+    /// with.
+    #[serde(default)]
+    children: Vec<ChildConfig>,
+    /// Check the session tokens clients present in their `Hello`.
+    #[serde(default)]
+    auth: Option<AuthConfig>,
+    /// Count at most one vote per voter in each round, however many connections it has.
+    #[serde(default)]
+    dedupe_votes: Dedupe,
+    /// Limit how fast each client may send messages.
+    #[serde(default)]
+    rate_limit: Option<RateLimitConfig>,
+    /// The `host:port` child funnels can reach us on, passed down the tree so that
+    /// grandchildren can fall back on us if their parent is lost.
+    #[serde(default)]
+    advertise: Option<String>,
+    /// The most child funnels we take at once.
+    #[serde(default)]
+    max_children: Option<usize>,
+    /// Report our load to a gateway, which sends new clients to the least loaded funnel.
+    #[serde(default)]
+    gateway: Option<GatewayConfig>,
+    /// Number of threads to spread clients over.
+    #[serde(default = "default_workers")]
+    workers: usize,
+    /// The most bytes that may wait to be sent to a client. A client that falls further
+    /// behind is disconnected.
+    #[serde(default = "default_max_queued_bytes")]
+    max_queued_bytes: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    certificate: PathBuf,
+    /// PEM private key, PKCS#8 or RSA.
+    key: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct AuthConfig {
+    /// The secret tokens are signed with, shared with whatever issues them.
+    secret: String,
+    /// Let clients without a valid token in to watch, though not to vote, instead of
+    /// disconnecting them.
+    #[serde(default)]
+    allow_unauthenticated: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct GatewayConfig {
+    /// Where the gateway takes load reports.
+    address: SocketAddr,
+    /// The WebSocket URL the gateway should give clients for us.
+    url: String,
+    /// The key our load reports are signed with, registered with the gateway under our id.
+    key: String,
+}
+
+/// A child funnel's registration with its parent, shared by both.
+#[derive(Serialize, Deserialize, Clone)]
+struct ChildConfig {
+    id: String,
+    key: String,
+}
+
+/// What counts as one voter when deduplicating votes.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum Dedupe {
+    Off,
+    /// The identity in the client's token, or its IP address when it has none.
+    Identity,
+    Ip,
+}
+
+impl Default for Dedupe {
+    fn default() -> Dedupe {
+        Dedupe::Off
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct RateLimitConfig {
+    /// Messages a client may send each second, on average.
+    messages_per_second: f64,
+    /// Messages a client may send at once.
+    burst: u32,
+    /// Disconnect a client once this many of its messages in a row have been dropped.
+    #[serde(default = "default_disconnect_after")]
+    disconnect_after: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct UpstreamTlsConfig {
+    /// The name upstream's certificate has to be valid for.
+    domain: String,
+    /// PEM certificates to trust instead of the usual web roots, such as the one that signed
+    /// a self-signed upstream's certificate.
+    #[serde(default)]
+    roots: Option<PathBuf>,
+}
+
+impl Config {
+    fn upstream_target(&self) -> Target {
+        Target {
+            address: self.upstream,
+            domain: self.upstream_tls.as_ref().map(|upstream_tls| upstream_tls.domain.clone()),
+            websocket: self.upstream_is_websocket,
+        }
+    }
+
+    fn node_id(&self) -> String {
+        match (&self.upstream_auth, &self.id) {
+            (&Some(ref auth), _) => auth.id.clone(),
+            (&None, &Some(ref id)) => id.clone(),
+            (&None, &None) => self.host.to_string(),
+        }
+    }
+
+    /// Settings for a root funnel on `host` beneath an engine in the same process, which has
+    /// no `upstream` to connect to.
+    pub fn in_process(host: SocketAddr) -> Config {
+        Config {
+            id: None,
+            host: host,
+            upstream: host,
+            upstream_is_websocket: false,
+            spectator_host: None,
+            timeout_change: None,
+            processing_time: default_processing_time(),
+            ping_interval: default_ping_interval(),
+            binary_encoding: false,
+            reuse_port: false,
+            tls: None,
+            upstream_tls: None,
+            upstream_auth: None,
+            children: Vec::new(),
+            auth: None,
+            dedupe_votes: Dedupe::Off,
+            rate_limit: None,
+            advertise: None,
+            max_children: None,
+            gateway: None,
+            workers: default_workers(),
+            max_queued_bytes: default_max_queued_bytes(),
+        }
+    }
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_max_queued_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_disconnect_after() -> u32 {
+    20
+}
+
+fn default_processing_time() -> Duration {
+    Duration::from_millis(50)
+}
+
+fn default_ping_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Assumed one-way latency to upstream, in milliseconds, until the first ping is answered.
+const UNMEASURED_LATENCY: u64 = 200;
+
+/// How long a new upstream link has to connect and welcome us before we give up on it.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait before going round the ancestors again when none of them took us.
+const REATTACH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pings upstream may leave unanswered before we count it as lost.
+const MAX_SILENT_PINGS: u32 = 3;
+
+/// Somewhere to connect upstream to: the upstream in the config, or an ancestor.
+#[derive(Debug, Clone)]
+struct Target {
+    address: SocketAddr,
+    /// The host name to ask for, and to check a TLS certificate against.
+    domain: Option<String>,
+    websocket: bool,
+}
+
+impl Target {
+    /// Funnels always link to each other over WebSockets.
+    fn from_ancestor(ancestor: &Ancestor) -> Option<Target> {
+        let advertised = match ancestor.address {
+            Some(ref address) => address,
+            None => return None,
+        };
+        let resolved = advertised.to_socket_addrs().ok().and_then(|mut addresses| addresses.next());
+        let address = match resolved {
+            Some(address) => address,
+            None => {
+                warn!("Could not resolve {} for ancestor {}", advertised, ancestor.id);
+                return None;
+            },
+        };
+        let host = advertised.rsplitn(2, ':').last().unwrap_or(advertised);
+        Some(Target {
+            address: address,
+            domain: host.parse::<IpAddr>().err().map(|_| host.to_owned()),
+            websocket: true,
+        })
+    }
+}
+
+/// Latency figures logged under the `metrics` target once per ping interval.
+#[derive(Serialize, Debug)]
+struct LinkMetrics {
+    upstream_round_trip: Option<u64>,
+    upstream_round_trip_variation: u64,
+    upstream_clock_offset: i64,
+    timeout_change: u64,
+    clients: usize,
+    spectators: usize,
+    client_round_trip_min: Option<u64>,
+    client_round_trip_mean: Option<u64>,
+    client_round_trip_max: Option<u64>,
+    votes_dropped: DroppedVotes,
+    clients_disconnected: u64,
+    clients_lagging: u64,
+}
+
+enum Upstream {
+    TcpSocket {
+        socket: BufReader<ClientStream>,
+        buffer: String,
+        /// Lines the socket has not taken yet.
+        outgoing: Vec<u8>,
+    },
+    WebSocket {
+        socket: WebSocket<ClientStream>,
+        encoding: Encoding,
+    },
+    /// A WebSocket link still in its opening handshake, which goes on as the socket is ready.
+    Handshaking(MidHandshake<ClientHandshake<ClientStream>>),
+    /// An engine in the same process, which messages are handed to whole.
+    Channel {
+        output: Sender<Protocol>,
+        /// Messages from the engine, then `None` once it has stopped.
+        input: Inbox<Option<Protocol>>,
+        received: VecDeque<Option<Protocol>>,
+    },
+    /// No link, from losing one until the next is made.
+    Detached,
+}
+
+impl Upstream {
+    /// Sockets are registered by `connect`, before a handshake can take them over.
+    fn register(&self, poll: &Poll) -> io::Result<()> {
+        match *self {
+            Upstream::Channel { ref input, .. } => input.register(poll, UPSTREAM),
+            _ => Ok(()),
+        }
+    }
+
+    fn from_tcp(socket: BufReader<ClientStream>) -> Upstream {
+        Upstream::TcpSocket {
+            socket: socket,
+            buffer: String::new(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    fn from_websocket(socket: WebSocket<ClientStream>) -> Upstream {
+        Upstream::WebSocket {
+            socket: socket,
+            encoding: Encoding::Json,
+        }
+    }
+
+    /// Passes messages from `input` on to the poll, and marks the end of them once the engine
+    /// drops its side.
+    fn from_channel(input: Receiver<Protocol>, output: Sender<Protocol>) -> Upstream {
+        let (mailbox, inbox) = mailbox();
+        thread::Builder::new()
+            .name("upstream".to_owned())
+            .spawn(move || {
+                for message in input {
+                    if mailbox.send(Some(message)).is_err() {
+                        return;
+                    }
+                }
+                let _ = mailbox.send(None);
+            })
+            .expect("Could not start upstream thread");
+        Upstream::Channel {
+            output: output,
+            input: inbox,
+            received: VecDeque::new(),
+        }
+    }
+}
+
+struct State<'a> {
+    config: &'a Config,
+    poll: Poll,
+    listener: TcpListener,
+    spectator_listener: Option<TcpListener>,
+    workers: Vec<Mailbox<Command>>,
+    next_worker: usize,
+    reports: Inbox<(usize, Report)>,
+    children: Children,
+    /// The latest figures from each worker.
+    client_metrics: Vec<ClientMetrics>,
+    /// The merged tally so far and the number of workers yet to send theirs.
+    collecting: Option<(Tally, usize)>,
+    /// When to send the tally without the workers yet to answer, so a stopped worker can't
+    /// hold the vote back past the processing time.
+    collect_deadline: Option<time::Instant>,
+    upstream: Upstream,
+    upstream_welcomed: bool,
+    upstream_clock: ClockOffset,
+    upstream_round_trip: RoundTrip,
+    latest_state: Option<Arc<StateChange>>,
+    round: Option<u64>,
+    next_vote_send: Option<time::Instant>,
+    next_ping: Option<time::Instant>,
+    /// When we last sent a vote upstream.
+    last_vote: Option<u64>,
+    /// Our parent and the funnels above it, nearest first, as our parent last told us.
+    ancestors: Vec<Ancestor>,
+    /// Set from losing upstream until a new parent welcomes us.
+    reattaching: bool,
+    /// Where to try attaching next while reattaching.
+    candidates: Vec<Target>,
+    next_reattach: Option<time::Instant>,
+    /// When the link being made has to have welcomed us by, or the next candidate is tried.
+    attach_deadline: Option<time::Instant>,
+    /// Pings sent since upstream last said anything.
+    silent_pings: u32,
+    /// For sending load reports to the gateway.
+    gateway: Option<UdpSocket>,
+    /// Why the funnel can't go on, once it can't. `serve` returns it before polling again.
+    stopped: Option<io::Error>,
+}
+
+pub fn read_config<P: AsRef<Path> + Clone>(path: P) -> Config {
+    let mut file = File::open(&path)
+        .unwrap_or_else(|e| panic!("Could not open config file {:?}: {}", path.as_ref(), e));
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).expect("Reading config file failed");
+    toml::from_str(&contents).expect("Format file incorrectly formatted")
+}
+
+fn duration_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Decodes a protocol message from a WebSocket frame, or from a line read off a TCP link.
+/// Binary frames hold the compact encoding on links that negotiated it and JSON otherwise.
+/// Returns `None` for control frames.
+fn decode_frame(frame: Message, encoding: Encoding) -> Option<Result<Protocol, String>> {
+    let result = match frame {
+        Message::Text(text) => serde_json::from_str(text.trim_right())
+            .map_err(|e| format!("{}: \"{}\"", e, text.trim_right())),
+        Message::Binary(data) => match encoding {
+            Encoding::Binary => codec::decode(&data).map_err(|e| format!("{}: {:?}", e, data)),
+            Encoding::Json => serde_json::from_slice(&data).map_err(|e| format!("{}", e)),
+        },
+        _ => return None,
+    };
+    Some(result)
+}
+
+const SERVER: Token = Token(0);
+const UPSTREAM: Token = Token(1);
+const WORKERS: Token = Token(2);
+const SPECTATORS: Token = Token(3);
+
+impl<'a> State<'a> {
+    /// Hands each new connection to the next worker in turn.
+    fn accept_clients(&mut self, spectator: bool) -> io::Result<()> {
+        loop {
+            let accepted = match (spectator, &self.spectator_listener) {
+                (true, &Some(ref listener)) => listener.accept(),
+                _ => self.listener.accept(),
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            let worker = self.next_worker;
+            self.next_worker = (self.next_worker + 1) % self.workers.len();
+            let command = Command::Accept {
+                stream: stream,
+                spectator: spectator,
+            };
+            if self.workers[worker].send(command).is_err() {
+                warn!("Worker {} has stopped", worker);
+            }
+        }
+    }
+
+    /// Reads the next complete message from upstream, or `None` if there is nothing more to
+    /// read until the next readiness event.
+    fn read_upstream(&mut self) -> io::Result<Option<Protocol>> {
+        loop {
+            let (frame, encoding) = match self.upstream {
+                Upstream::TcpSocket { ref mut socket, ref mut buffer, .. } => {
+                    match socket.read_line(buffer) {
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+                        Err(e) => return Err(e),
+                        Ok(0) => {
+                            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Upstream closed"))
+                        },
+                        Ok(_) => {
+                            let message = buffer.clone();
+                            buffer.clear();
+                            (Message::Text(message), Encoding::Json)
+                        },
+                    }
+                },
+                Upstream::Channel { ref input, ref mut received, .. } => {
+                    if received.is_empty() {
+                        received.extend(input.drain());
+                    }
+                    match received.pop_front() {
+                        Some(Some(message)) => return Ok(Some(message)),
+                        Some(None) => {
+                            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Engine stopped"))
+                        },
+                        None => return Ok(None),
+                    }
+                },
+                Upstream::Detached | Upstream::Handshaking(_) => return Ok(None),
+                Upstream::WebSocket { ref mut socket, encoding } => {
+                    match socket.read_message() {
+                        Ok(message) => (message, encoding),
+                        Err(e) => match e.into_non_blocking() {
+                            None => return Ok(None),
+                            Some(e) => {
+                                return Err(io::Error::new(ErrorKind::Other, format!("{}", e)))
+                            },
+                        },
+                    }
+                },
+            };
+
+            match decode_frame(frame, encoding) {
+                Some(Ok(message)) => return Ok(Some(message)),
+                Some(Err(e)) => warn!("Badly formatted message from upstream: {}", e),
+                None => (),
+            }
+        }
+    }
+
+    fn upstream_event(&mut self, event: &Event) -> io::Result<()> {
+        if let Upstream::Handshaking(_) = self.upstream {
+            self.continue_handshake()?;
+        }
+        if event.readiness().is_writable() {
+            self.flush_upstream()?;
+        }
+        if event.readiness().is_readable() {
+            while let Some(message) = self.read_upstream()? {
+                self.silent_pings = 0;
+                self.upstream_message(message)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Takes the upstream WebSocket handshake as far as the socket allows, and says hello once
+    /// it is done.
+    fn continue_handshake(&mut self) -> io::Result<()> {
+        let in_progress = match mem::replace(&mut self.upstream, Upstream::Detached) {
+            Upstream::Handshaking(in_progress) => in_progress,
+            upstream => {
+                self.upstream = upstream;
+                return Ok(());
+            },
+        };
+        match in_progress.handshake() {
+            Ok((websocket, _)) => {
+                info!("Connected to upstream (WebSocket)");
+                self.upstream = Upstream::from_websocket(websocket);
+                self.say_hello();
+                Ok(())
+            },
+            Err(Interrupted(in_progress)) => {
+                self.upstream = Upstream::Handshaking(in_progress);
+                Ok(())
+            },
+            Err(HandshakeError::Failure(e)) => {
+                Err(io::Error::new(ErrorKind::Other, format!("{}", e)))
+            },
+        }
+    }
+
+    /// Handles a message from upstream. Fails if upstream refuses us while we look for a new
+    /// parent, so that the next one can be tried.
+    fn upstream_message(&mut self, mut message: Protocol) -> io::Result<()> {
+        debug!("Received from upstream: {:?}", message);
+
+        match message {
+            Protocol::Welcome(welcome) => {
+                info!("Upstream accepted protocol version {} with {:?} encoding",
+                      welcome.version, welcome.encoding);
+                self.upstream_welcomed = true;
+                self.reattaching = false;
+                self.attach_deadline = None;
+                self.silent_pings = 0;
+                if let Upstream::WebSocket { ref mut encoding, .. } = self.upstream {
+                    *encoding = welcome.encoding;
+                }
+                return Ok(());
+            },
+            Protocol::Challenge(ref challenge) => {
+                let signature = match self.config.upstream_auth {
+                    Some(ref auth) => token::answer(auth.key.as_bytes(), &challenge.nonce),
+                    None => {
+                        let message = "Upstream challenged us without an upstream_auth to \
+                                       answer with";
+                        self.stopped = Some(io::Error::new(ErrorKind::PermissionDenied, message));
+                        return Ok(());
+                    },
+                };
+                self.send_upstream(&Protocol::Answer(Answer { signature: signature }));
+                return Ok(());
+            },
+            Protocol::Error(ref error) if !self.upstream_welcomed && self.reattaching => {
+                let message = format!("Upstream refused connection: {}", error.error);
+                return Err(io::Error::new(ErrorKind::ConnectionRefused, message));
+            },
+            Protocol::Error(ref error) if !self.upstream_welcomed => {
+                let message = format!("Upstream refused connection: {}", error.error);
+                self.stopped = Some(io::Error::new(ErrorKind::ConnectionRefused, message));
+                return Ok(());
+            },
+            Protocol::Error(ref error) => {
+                warn!("Error from upstream: {}", error.error);
+                return Ok(());
+            },
+            Protocol::Pong(ref pong) => {
+                let now = now_millis();
+                self.upstream_clock.record(pong, now);
+                self.upstream_round_trip.record(now.saturating_sub(pong.sent));
+                info!("Upstream clock offset {} ms, round trip {:?} ms, timeout change {:?}",
+                      self.upstream_clock.offset(), self.upstream_round_trip.smoothed(),
+                      self.timeout_change());
+                return Ok(());
+            },
+            Protocol::VoteCall(ref mut vote_call) => {
+                info!("Vote call for round {}!", vote_call.round);
+                self.round = Some(vote_call.round);
+
+                let deadline = self.upstream_clock.to_local(vote_call.deadline);
+                let send_by = deadline.saturating_sub(duration_millis(self.timeout_change()));
+                let wait = send_by.saturating_sub(now_millis());
+                self.next_vote_send = Some(time::Instant::now() + Duration::from_millis(wait));
+                info!("Next vote send in {}", wait);
+
+                // our children have to report back to us before we report upstream
+                vote_call.deadline = send_by;
+            },
+            Protocol::Lineage(ref lineage) => {
+                self.ancestors = lineage.ancestors.clone();
+                self.send_lineage();
+                return Ok(());
+            },
+            Protocol::StateChange(ref state) => {
+                self.update_state(state.clone());
+                return Ok(());
+            },
+            Protocol::StateDelta(ref delta) => {
+                let state = self.latest_state.as_ref().and_then(|state| delta.apply(state));
+                match state {
+                    Some(state) => self.update_state(state),
+                    None => {
+                        warn!("Missing base {} of state delta, resyncing", delta.base);
+                        self.send_upstream(&Protocol::Resync);
+                    },
+                }
+                return Ok(());
+            },
+            _ => {
+                warn!("Unexpected message from upstream: {:?}", message);
+                return Ok(());
+            },
+        }
+
+        if let Protocol::VoteCall(vote_call) = message {
+            let frames = Frames::new(&message, self.config.binary_encoding);
+            self.broadcast(|| Command::VoteCall {
+                round: vote_call.round,
+                frames: frames.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Records a new state and passes it on, as a delta to the clients that understand them.
+    fn update_state(&mut self, state: StateChange) {
+        let binary = self.config.binary_encoding;
+        let delta = self.latest_state
+            .as_ref()
+            .map(|old| Frames::new(&Protocol::StateDelta(StateDelta::between(old, &state)), binary));
+        let full = Frames::new(&Protocol::StateChange(state.clone()), binary);
+
+        let state = Arc::new(state);
+        self.latest_state = Some(state.clone());
+        self.broadcast(|| Command::State {
+            state: state.clone(),
+            full: full.clone(),
+            delta: delta.clone(),
+        });
+    }
+
+    /// Sends a command to every worker.
+    fn broadcast<F: Fn() -> Command>(&mut self, command: F) {
+        for (index, worker) in self.workers.iter().enumerate() {
+            if worker.send(command()).is_err() {
+                warn!("Worker {} has stopped", index);
+            }
+        }
+    }
+
+    fn worker_event(&mut self) {
+        for (worker, report) in self.reports.drain() {
+            match report {
+                Report::Tally(tally) => self.worker_tally(tally),
+                Report::Clients(metrics) => self.client_metrics[worker] = metrics,
+            }
+        }
+    }
+
+    /// Asks every worker for its votes. The vote goes upstream once they have all answered.
+    fn collect_votes(&mut self) {
+        if self.collecting.is_some() {
+            warn!("Still waiting on votes from the last round");
+            return;
+        }
+
+        let mut waiting = 0;
+        for (index, worker) in self.workers.iter().enumerate() {
+            match worker.send(Command::Collect) {
+                Ok(()) => waiting += 1,
+                Err(_) => warn!("Worker {} has stopped", index),
+            }
+        }
+
+        if waiting == 0 {
+            self.send_vote_upstream(Tally::default());
+        } else {
+            self.collecting = Some((Tally::default(), waiting));
+            self.collect_deadline = Some(time::Instant::now() + self.config.processing_time);
+        }
+    }
+
+    fn worker_tally(&mut self, tally: Tally) {
+        let done = match self.collecting {
+            Some((ref mut total, ref mut waiting)) => {
+                total.merge(tally);
+                *waiting -= 1;
+                *waiting == 0
+            },
+            None => {
+                warn!("Unexpected tally from worker");
+                false
+            },
+        };
+
+        if done {
+            self.send_collected();
+        }
+    }
+
+    /// Sends the votes collected so far upstream, whether or not every worker has answered.
+    fn send_collected(&mut self) {
+        self.collect_deadline = None;
+        if let Some((tally, waiting)) = self.collecting.take() {
+            if waiting > 0 {
+                warn!("{} workers did not send their votes in time", waiting);
+            }
+            self.send_vote_upstream(tally);
+        }
+    }
+
+    fn send_vote_upstream(&mut self, tally: Tally) {
+        info!("Sending votes for {} upstream", tally.voters);
+
+        let mut vote = match tally.pick {
+            Some(vote) => vote,
+            None => {
+                info!("No votes found apparently");
+                Vote {
+                    action: Action {
+                        to: (0, 0),
+                        from: (0, 0),
+                    },
+                    weight: 0,
+                    round: None,
+                }
+            },
+        };
+        vote.weight = tally.weight;
+        vote.round = self.round;
+
+        let message = Protocol::Vote(vote);
+        debug!("{:?}", message);
+        self.send_upstream(&message);
+        self.last_vote = Some(now_millis());
+
+        info!("Votes sent!");
+    }
+
+    /// How much earlier than the parent's deadline local voting closes.
+    fn timeout_change(&self) -> Duration {
+        if let Some(timeout_change) = self.config.timeout_change {
+            return timeout_change;
+        }
+
+        let latency = self.upstream_round_trip.one_way_margin().unwrap_or(UNMEASURED_LATENCY);
+        Duration::from_millis(latency) + self.config.processing_time
+    }
+
+    fn client_metrics(&self) -> ClientMetrics {
+        let mut clients = ClientMetrics::default();
+        for metrics in &self.client_metrics {
+            clients.merge(metrics);
+        }
+        clients
+    }
+
+    fn log_metrics(&self) {
+        let clients = self.client_metrics();
+
+        let metrics = LinkMetrics {
+            upstream_round_trip: self.upstream_round_trip.smoothed(),
+            upstream_round_trip_variation: self.upstream_round_trip.variation(),
+            upstream_clock_offset: self.upstream_clock.offset(),
+            timeout_change: duration_millis(self.timeout_change()),
+            clients: clients.clients,
+            spectators: clients.spectators,
+            client_round_trip_min: clients.round_trip_min,
+            client_round_trip_mean: clients.round_trip_mean(),
+            client_round_trip_max: clients.round_trip_max,
+            votes_dropped: clients.dropped,
+            clients_disconnected: clients.disconnected,
+            clients_lagging: clients.lagging,
+        };
+        info!(target: "metrics", "{}", serde_json::to_string(&metrics).unwrap());
+    }
+
+    /// This funnel and the latest reports from the funnels below it.
+    fn topology(&self) -> Node {
+        let clients = self.client_metrics();
+        Node {
+            id: self.config.node_id(),
+            clients: clients.clients - clients.funnels,
+            spectators: clients.spectators,
+            round_trip: self.upstream_round_trip.smoothed(),
+            last_vote: self.last_vote,
+            children: self.children.topology(),
+        }
+    }
+
+    fn report_topology(&mut self) {
+        if self.upstream_welcomed {
+            let topology = Protocol::Topology(self.topology());
+            self.send_upstream(&topology);
+        }
+    }
+
+    fn report_load(&self) {
+        let (socket, gateway) = match (&self.gateway, &self.config.gateway) {
+            (&Some(ref socket), &Some(ref gateway)) => (socket, gateway),
+            _ => return,
+        };
+        let clients = self.client_metrics();
+        let load = Load {
+            id: self.config.node_id(),
+            url: gateway.url.clone(),
+            clients: clients.clients - clients.funnels,
+            attached: self.upstream_welcomed,
+            sent: now_millis(),
+        };
+        let report = LoadReport::new(&load, gateway.key.as_bytes());
+        let report = serde_json::to_vec(&report).unwrap();
+        if let Err(e) = socket.send_to(&report, gateway.address) {
+            warn!("Sending load to gateway failed: {}", e);
+        }
+    }
+
+    fn say_hello(&mut self) {
+        let encodings = if self.config.upstream_is_websocket && self.config.binary_encoding {
+            vec![Encoding::Binary]
+        } else {
+            Vec::new()
+        };
+        let mut hello = Hello::new(encodings, true);
+        hello.funnel = self.config.upstream_auth.as_ref().map(|auth| auth.id.clone());
+        hello.topology = Some(self.topology());
+        self.send_upstream(&Protocol::Hello(hello));
+    }
+
+    /// Tells our child funnels where they sit, so they know who to fall back on.
+    fn send_lineage(&mut self) {
+        let mut ancestors = vec![Ancestor {
+            id: self.config.node_id(),
+            address: self.config.advertise.clone(),
+        }];
+        ancestors.extend(self.ancestors.iter().cloned());
+        let lineage = Protocol::Lineage(Lineage { ancestors: ancestors });
+        let frames = Frames::new(&lineage, self.config.binary_encoding);
+        self.broadcast(|| Command::Lineage(frames.clone()));
+    }
+
+    /// Where to look for a new parent, nearest first: the ancestors above the parent we
+    /// lost, then the upstream in our config.
+    fn fallbacks(&self) -> Vec<Target> {
+        let id = self.config.node_id();
+        let mut targets: Vec<Target> = self.ancestors
+            .iter()
+            .skip(1)
+            .filter(|ancestor| ancestor.id != id)
+            .filter_map(Target::from_ancestor)
+            .collect();
+        targets.push(self.config.upstream_target());
+        targets
+    }
+
+    fn upstream_lost(&mut self) {
+        if let Upstream::Channel { .. } = self.upstream {
+            let message = "Lost the engine running alongside us";
+            self.stopped = Some(io::Error::new(ErrorKind::BrokenPipe, message));
+            return;
+        }
+        self.upstream = Upstream::Detached;
+        self.upstream_welcomed = false;
+        self.attach_deadline = None;
+        if !self.reattaching {
+            self.reattaching = true;
+            self.candidates = self.fallbacks();
+        }
+        self.attach_next();
+    }
+
+    /// Connects to the nearest candidate that answers, and says hello. Whether it takes us
+    /// is up to its reply, which has to come within `CONNECT_TIMEOUT`. Goes round again after
+    /// a while if none answer.
+    fn attach_next(&mut self) {
+        while !self.candidates.is_empty() {
+            let target = self.candidates.remove(0);
+            info!("Attaching to {}", target.address);
+            let upstream = match connect(self.config, &target, &self.poll) {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    warn!("Could not attach to {}: {}", target.address, e);
+                    continue;
+                },
+            };
+
+            self.upstream = upstream;
+            self.upstream_clock = ClockOffset::new();
+            self.upstream_round_trip = RoundTrip::new();
+            self.silent_pings = 0;
+            self.attach_deadline = Some(time::Instant::now() + CONNECT_TIMEOUT);
+            // a WebSocket says hello once its handshake is done
+            if let Upstream::Handshaking(_) = self.upstream {
+                return;
+            }
+            self.say_hello();
+            return;
+        }
+
+        warn!("No upstream to attach to, trying again in {:?}", REATTACH_INTERVAL);
+        self.next_reattach = Some(time::Instant::now() + REATTACH_INTERVAL);
+    }
+
+    fn ping_upstream(&mut self) {
+        let ping = Protocol::Ping(Ping { sent: now_millis() });
+        self.send_upstream(&ping);
+        self.silent_pings += 1;
+    }
+
+    /// Queues a message for upstream and sends as much as the socket takes. The rest goes out
+    /// on the next writable event.
+    fn send_upstream(&mut self, message: &Protocol) {
+        match self.upstream {
+            Upstream::TcpSocket { ref mut outgoing, .. } => {
+                serde_json::to_writer(&mut *outgoing, message).unwrap();
+                outgoing.push(b'\n');
+            },
+            Upstream::WebSocket { ref mut socket, encoding } => {
+                let message = match encoding {
+                    Encoding::Json => Message::text(serde_json::to_string(message).unwrap()),
+                    Encoding::Binary => Message::binary(codec::encode(message)),
+                };
+                // the message stays queued when the socket is full
+                if let Err(e) = socket.write_message(message) {
+                    if let Some(e) = e.into_non_blocking() {
+                        warn!("Sending message upstream failed: {}", e);
+                        return;
+                    }
+                }
+            },
+            Upstream::Channel { ref output, .. } => {
+                if output.send(message.clone()).is_err() {
+                    warn!("Sending message upstream failed");
+                }
+            },
+            Upstream::Detached | Upstream::Handshaking(_) => {
+                debug!("Not attached upstream, dropping {:?}", message)
+            },
+        }
+        if let Err(e) = self.flush_upstream() {
+            warn!("Sending message upstream failed: {}", e);
+        }
+    }
+
+    /// Writes what the upstream socket takes of anything queued for it, TLS included.
+    fn flush_upstream(&mut self) -> io::Result<()> {
+        let stream = match self.upstream {
+            Upstream::TcpSocket { ref mut socket, ref mut outgoing, .. } => {
+                while !outgoing.is_empty() {
+                    match socket.get_mut().write(outgoing) {
+                        Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "Socket closed")),
+                        Ok(length) => {
+                            outgoing.drain(..length);
+                        },
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                        Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+                socket.get_mut()
+            },
+            Upstream::WebSocket { ref mut socket, .. } => {
+                if let Err(e) = socket.write_pending() {
+                    return match e.into_non_blocking() {
+                        None => Ok(()),
+                        Some(e) => Err(io::Error::new(ErrorKind::Other, format!("{}", e))),
+                    };
+                }
+                socket.get_mut()
+            },
+            _ => return Ok(()),
+        };
+        match stream.flush() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+}
+
+fn connect_upstream(config: &Config, target: &Target) -> io::Result<ClientStream> {
+    let socket = TcpStream::connect(&target.address)?;
+    match config.upstream_tls {
+        Some(ref upstream_tls) => {
+            let roots = upstream_tls.roots.as_ref().map(|path| path.as_path());
+            let tls_config = tls::client_config(roots)?;
+            let domain = target.domain.as_ref().unwrap_or(&upstream_tls.domain);
+            Ok(Stream::Tls(TlsStream::connect(socket, &tls_config, domain)?))
+        },
+        None => Ok(Stream::Plain(socket)),
+    }
+}
+
+/// Opens a link to `target` and registers it with `poll`. A WebSocket link is returned part
+/// way through its handshake, for `continue_handshake` to finish as the socket is ready.
+fn connect(config: &Config, target: &Target, poll: &Poll) -> io::Result<Upstream> {
+    let stream = connect_upstream(config, target)?;
+    let ready = Ready::readable() | Ready::writable();
+    poll.register(stream.get_ref(), UPSTREAM, ready, PollOpt::edge())?;
+    if !target.websocket {
+        info!{"Connected to upstream (TCP) {}", target.address};
+        return Ok(Upstream::from_tcp(BufReader::new(stream)));
+    }
+
+    let mut url = url::Url::parse("ws://1.0.0.0").unwrap();
+    let _ = url.set_ip_host(target.address.ip());
+    if let Some(ref domain) = target.domain {
+        url.set_host(Some(domain))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", domain, e)))?;
+    }
+    let _ = url.set_port(Some(target.address.port()));
+    let request = handshake::client::Request::from(url);
+
+    match tungstenite::client(request, stream) {
+        Ok((websocket, _)) => {
+            info!{"Connected to upstream (WebSocket) {}", target.address};
+            Ok(Upstream::from_websocket(websocket))
+        },
+        Err(Interrupted(in_progress)) => Ok(Upstream::Handshaking(in_progress)),
+        Err(HandshakeError::Failure(e)) => Err(io::Error::new(ErrorKind::Other, format!("{}", e))),
+    }
+}
+
+/// Runs a funnel linked to the upstream in `config`, retrying until it can be reached.
+/// Returns only if upstream turns us away for good.
+pub fn run(config: Config) -> io::Result<()> {
+    serve(config, Upstream::Detached)
+}
+
+/// Runs a funnel beneath an engine in the same process. The engine's messages arrive on
+/// `input` and ours go out on `output`. Returns once the engine stops.
+pub fn run_in_process(
+    config: Config,
+    input: Receiver<Protocol>,
+    output: Sender<Protocol>,
+) -> io::Result<()> {
+    serve(config, Upstream::from_channel(input, output))
+}
+
+fn serve(config: Config, upstream: Upstream) -> io::Result<()> {
+    let listener = listener::bind(&config.host, config.reuse_port).expect("Could not bind to host");
+    info!{"Listening on {}", config.host};
+
+    let spectator_listener = config.spectator_host.map(|host| {
+        let listener = listener::bind(&host, config.reuse_port)
+            .expect("Could not bind to spectator host");
+        info!("Listening for spectators on {}", host);
+        listener
+    });
+
+    let poll = Poll::new().unwrap();
+    poll.register(&listener, SERVER, Ready::readable(), PollOpt::edge()).unwrap();
+    if let Some(ref spectator_listener) = spectator_listener {
+        poll.register(spectator_listener, SPECTATORS, Ready::readable(), PollOpt::edge()).unwrap();
+    }
+    upstream.register(&poll).unwrap();
+
+    let gateway = config.gateway.as_ref().map(|gateway| {
+        let any: SocketAddr = if gateway.address.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(any).expect("Could not open socket for the gateway");
+        socket.set_nonblocking(true).expect("Could not open socket for the gateway");
+        socket
+    });
+
+    let (report_mailbox, reports) = mailbox();
+    reports.register(&poll, WORKERS).unwrap();
+
+    let tls = config.tls.as_ref().map(|tls| {
+        tls::server_config(&tls.certificate, &tls.key).expect("Could not load TLS certificate")
+    });
+
+    let ballots = Ballots::default();
+    let children = Children::new(&config.children, config.max_children);
+    let worker_count = config.workers.max(1);
+    let mut workers = Vec::new();
+    for id in 0..worker_count {
+        let (mailbox, commands) = mailbox();
+        let config = config.clone();
+        let reports = report_mailbox.clone();
+        let tls = tls.clone();
+        let ballots = ballots.clone();
+        let children = children.clone();
+        thread::Builder::new()
+            .name(format!("worker-{}", id))
+            .spawn(move || {
+                Worker::new(id, config, tls, commands, reports, ballots, children).run()
+            })
+            .expect("Could not start worker thread");
+        workers.push(mailbox);
+    }
+    info!("Started {} workers", worker_count);
+
+    let mut events = Events::with_capacity(1024);
+
+    let mut state = State {
+        config: &config,
+        poll: poll,
+        listener: listener,
+        spectator_listener: spectator_listener,
+        workers: workers,
+        next_worker: 0,
+        reports: reports,
+        children: children,
+        client_metrics: vec![ClientMetrics::default(); worker_count],
+        collecting: None,
+        collect_deadline: None,
+        upstream: upstream,
+        upstream_welcomed: false,
+        upstream_clock: ClockOffset::new(),
+        upstream_round_trip: RoundTrip::new(),
+        latest_state: None,
+        round: None,
+        next_vote_send: None,
+        next_ping: Some(time::Instant::now()),
+        last_vote: None,
+        ancestors: Vec::new(),
+        reattaching: false,
+        candidates: Vec::new(),
+        next_reattach: None,
+        attach_deadline: None,
+        silent_pings: 0,
+        gateway: gateway,
+        stopped: None,
+    };
+
+    state.send_lineage();
+    match state.upstream {
+        Upstream::Detached => {
+            state.candidates = vec![config.upstream_target()];
+            state.attach_next();
+        },
+        _ => state.say_hello(),
+    }
+
+    loop {
+        let time = time::Instant::now();
+        let mut timeout = None;
+
+        let mut sending_yet = false;
+        if let Some(next_vote_send) = state.next_vote_send {
+            if time >= next_vote_send {
+                sending_yet = true;
+            } else {
+                sending_yet = false;
+                timeout = Some(next_vote_send - time);
+            }
+        }
+        if sending_yet {
+            info!("About to send vote");
+            state.collect_votes();
+            state.next_vote_send = None;
+        }
+
+        if let Some(collect_deadline) = state.collect_deadline {
+            if time >= collect_deadline {
+                state.send_collected();
+            } else {
+                let until_deadline = collect_deadline - time;
+                if timeout.map_or(true, |timeout| until_deadline < timeout) {
+                    timeout = Some(until_deadline);
+                }
+            }
+        }
+
+        if let Some(next_reattach) = state.next_reattach {
+            if time >= next_reattach {
+                state.next_reattach = None;
+                state.candidates = state.fallbacks();
+                state.attach_next();
+            } else {
+                let until_reattach = next_reattach - time;
+                if timeout.map_or(true, |timeout| until_reattach < timeout) {
+                    timeout = Some(until_reattach);
+                }
+            }
+        }
+
+        if let Some(attach_deadline) = state.attach_deadline {
+            if time >= attach_deadline {
+                warn!("Upstream did not welcome us within {:?}", CONNECT_TIMEOUT);
+                state.upstream_lost();
+            } else {
+                let until_deadline = attach_deadline - time;
+                if timeout.map_or(true, |timeout| until_deadline < timeout) {
+                    timeout = Some(until_deadline);
+                }
+            }
+        }
+
+        if let Some(next_ping) = state.next_ping {
+            if time >= next_ping {
+                state.log_metrics();
+                if state.silent_pings >= MAX_SILENT_PINGS && !state.reattaching {
+                    warn!("Upstream has not answered {} pings", state.silent_pings);
+                    state.upstream_lost();
+                }
+                state.report_topology();
+                state.report_load();
+                state.ping_upstream();
+                state.next_ping = Some(time + config.ping_interval);
+            }
+        }
+        if let Some(next_ping) = state.next_ping {
+            let until_ping = next_ping - time;
+            if timeout.map_or(true, |timeout| until_ping < timeout) {
+                timeout = Some(until_ping);
+            }
+        }
+
+        if let Some(e) = state.stopped.take() {
+            return Err(e);
+        }
+
+        state.poll.poll(&mut events, timeout).unwrap();
+
+        for event in &events {
+            match event.token() {
+                SERVER => match state.accept_clients(false) {
+                    Err(e) => warn!("Client accept failed: {:?}", e),
+                    _ => (),
+                },
+                SPECTATORS => match state.accept_clients(true) {
+                    Err(e) => warn!("Spectator accept failed: {:?}", e),
+                    _ => (),
+                },
+                UPSTREAM => {
+                    if let Err(e) = state.upstream_event(&event) {
+                        warn!("Upstream connection failed: {}", e);
+                        state.upstream_lost();
+                    }
+                },
+                WORKERS => state.worker_event(),
+                Token(_) =>
+                    (),
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Keeping any one voter or connection from outweighing the rest.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Limits a connection to `rate` messages a second on average, in bursts of up to `burst`.
+pub struct TokenBucket {
+    tokens: f64,
+    rate: f64,
+    burst: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full, so a new connection can send its first burst straight away.
+    pub fn new(rate: f64, burst: u32) -> TokenBucket {
+        TokenBucket {
+            tokens: burst as f64,
+            rate: rate,
+            burst: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Spends a token if there is one.
+    pub fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Which client, as a worker id and client index, holds each voter's one vote in the current
+/// round. Shared by every worker, since one voter's connections may be spread over several.
+#[derive(Clone, Default)]
+pub struct Ballots {
+    holders: Arc<Mutex<HashMap<String, (usize, usize)>>>,
+}
+
+impl Ballots {
+    /// Gives the voter's ballot to `holder` unless another client already has it.
+    pub fn claim(&self, voter: &str, holder: (usize, usize)) -> bool {
+        let mut holders = self.holders.lock().unwrap();
+        match holders.entry(voter.to_owned()) {
+            Entry::Occupied(entry) => *entry.get() == holder,
+            Entry::Vacant(entry) => {
+                entry.insert(holder);
+                true
+            },
+        }
+    }
+
+    /// Frees the voter's ballot once `holder`'s vote has been counted or it has gone.
+    pub fn release(&self, voter: &str, holder: (usize, usize)) {
+        let mut holders = self.holders.lock().unwrap();
+        if holders.get(voter) == Some(&holder) {
+            holders.remove(voter);
+        }
+    }
+}
+
+/// Counts of votes thrown away, by reason.
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct DroppedVotes {
+    /// Votes from clients without a valid token.
+    pub unauthenticated: u64,
+    /// Votes from spectators.
+    pub spectator: u64,
+    /// Weighted votes from clients that are not trusted child funnels.
+    pub untrusted_weight: u64,
+    /// Votes for a round other than the open one, or sent while no round is open.
+    pub wrong_round: u64,
+    /// Votes from a voter that another connection already voted for this round.
+    pub duplicate: u64,
+    /// Messages over a connection's rate limit. These are dropped unread, so this counts
+    /// every kind of message rather than only votes.
+    pub rate_limited: u64,
+}
+
+impl DroppedVotes {
+    pub fn merge(&mut self, other: &DroppedVotes) {
+        self.unauthenticated += other.unauthenticated;
+        self.spectator += other.spectator;
+        self.untrusted_weight += other.untrusted_weight;
+        self.wrong_round += other.wrong_round;
+        self.duplicate += other.duplicate;
+        self.rate_limited += other.rate_limited;
+    }
+}
@@ -0,0 +1,37 @@
+//! Binding the client listener, optionally shared with sibling funnel processes.
+
+use std::io;
+use std::net::SocketAddr;
+
+use mio::net::TcpListener;
+use net2::TcpBuilder;
+#[cfg(unix)]
+use net2::unix::UnixTcpBuilderExt;
+
+/// Binds a listener on `address`. With `reuse_port` set, any number of processes can bind
+/// the same address with `reuse_port` and the kernel spreads incoming connections between
+/// them.
+pub fn bind(address: &SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    if !reuse_port {
+        return TcpListener::bind(address);
+    }
+
+    let builder = match *address {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.reuse_address(true)?;
+    set_reuse_port(&builder)?;
+    let listener = builder.bind(address)?.listen(1024)?;
+    TcpListener::from_std(listener)
+}
+
+#[cfg(unix)]
+fn set_reuse_port(builder: &TcpBuilder) -> io::Result<()> {
+    builder.reuse_port(true).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_: &TcpBuilder) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "reuse_port is only supported on Unix"))
+}
@@ -0,0 +1,63 @@
+use mio::{Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+
+use std::io;
+use std::sync::mpsc::{self, Receiver, SendError, Sender};
+
+/// Creates a channel whose receiving end wakes up a `Poll`, so that threads can pass messages
+/// to each other's event loops.
+pub fn mailbox<T>() -> (Mailbox<T>, Inbox<T>) {
+    let (sender, receiver) = mpsc::channel();
+    let (registration, readiness) = Registration::new2();
+
+    let mailbox = Mailbox {
+        sender: sender,
+        readiness: readiness.clone(),
+    };
+    let inbox = Inbox {
+        receiver: receiver,
+        registration: registration,
+        readiness: readiness,
+    };
+    (mailbox, inbox)
+}
+
+pub struct Mailbox<T> {
+    sender: Sender<T>,
+    readiness: SetReadiness,
+}
+
+impl<T> Mailbox<T> {
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        self.sender.send(message)?;
+        let _ = self.readiness.set_readiness(Ready::readable());
+        Ok(())
+    }
+}
+
+impl<T> Clone for Mailbox<T> {
+    fn clone(&self) -> Mailbox<T> {
+        Mailbox {
+            sender: self.sender.clone(),
+            readiness: self.readiness.clone(),
+        }
+    }
+}
+
+pub struct Inbox<T> {
+    receiver: Receiver<T>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+impl<T> Inbox<T> {
+    pub fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.register(&self.registration, token, Ready::readable(), PollOpt::edge())
+    }
+
+    /// Takes everything sent so far. Readiness is cleared before reading, so anything sent
+    /// while this runs wakes the poll again.
+    pub fn drain(&self) -> Vec<T> {
+        let _ = self.readiness.set_readiness(Ready::empty());
+        self.receiver.try_iter().collect()
+    }
+}
@@ -0,0 +1,89 @@
+//! The child funnels a funnel knows about, which of them are connected, and what their
+//! subtrees last looked like.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+
+use common::Node;
+
+use ChildConfig;
+
+struct Connected {
+    /// The worker id and client index the child is on.
+    client: (usize, usize),
+    topology: Option<Node>,
+}
+
+/// Shared by every worker, so a child is only ever connected once however connections are
+/// spread between them.
+#[derive(Clone)]
+pub struct Children {
+    keys: Arc<HashMap<String, String>>,
+    /// How many children may be connected at once.
+    limit: Option<usize>,
+    connected: Arc<Mutex<HashMap<String, Connected>>>,
+}
+
+impl Children {
+    pub fn new(children: &[ChildConfig], limit: Option<usize>) -> Children {
+        let keys = children.iter().map(|child| (child.id.clone(), child.key.clone())).collect();
+        Children {
+            keys: Arc::new(keys),
+            limit: limit,
+            connected: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The key a registered child answers challenges with.
+    pub fn key(&self, id: &str) -> Option<&str> {
+        self.keys.get(id).map(|key| &key[..])
+    }
+
+    /// Marks a child connected on `client`, unless it already is on another or we have as
+    /// many children as we take.
+    pub fn connect(&self, id: &str, client: (usize, usize)) -> Result<(), &'static str> {
+        let mut connected = self.connected.lock().unwrap();
+        let full = self.limit.map_or(false, |limit| connected.len() >= limit);
+        match connected.entry(id.to_owned()) {
+            Entry::Occupied(ref entry) if entry.get().client == client => Ok(()),
+            Entry::Occupied(_) => Err("already connected"),
+            Entry::Vacant(_) if full => Err("no room for more children"),
+            Entry::Vacant(entry) => {
+                entry.insert(Connected {
+                    client: client,
+                    topology: None,
+                });
+                Ok(())
+            },
+        }
+    }
+
+    pub fn disconnect(&self, id: &str, client: (usize, usize)) {
+        let mut connected = self.connected.lock().unwrap();
+        if connected.get(id).map(|child| child.client) == Some(client) {
+            connected.remove(id);
+        }
+    }
+
+    /// Records the subtree a connected child reported.
+    pub fn report(&self, id: &str, client: (usize, usize), topology: Node) {
+        let mut connected = self.connected.lock().unwrap();
+        if let Some(child) = connected.get_mut(id) {
+            if child.client == client {
+                child.topology = Some(topology);
+            }
+        }
+    }
+
+    /// The latest subtree from each connected child that has reported one, ordered by id.
+    pub fn topology(&self) -> Vec<Node> {
+        let connected = self.connected.lock().unwrap();
+        let mut nodes: Vec<Node> = connected
+            .values()
+            .filter_map(|child| child.topology.clone())
+            .collect();
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+        nodes
+    }
+}
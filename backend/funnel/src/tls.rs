@@ -0,0 +1,217 @@
+//! Optional TLS on the client listener and the upstream link, for non-blocking sockets.
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use mio::net::TcpStream;
+use rustls::{ClientSession, NoClientAuth, ServerSession, Session};
+use rustls::internal::pemfile;
+use webpki::DNSNameRef;
+use webpki_roots;
+
+pub use rustls::{ClientConfig, ServerConfig};
+
+/// The most plaintext a session holds for a slow socket before writes report `WouldBlock`.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+fn invalid<E: ::std::fmt::Debug>(what: &str, path: &Path, error: E) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid {} in {}: {:?}", what, path.display(), error),
+    )
+}
+
+/// Loads a PEM certificate chain and its PKCS#8 or RSA private key for the client listener.
+pub fn server_config(certificate: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let chain = pemfile::certs(&mut BufReader::new(File::open(certificate)?))
+        .map_err(|e| invalid("certificate", certificate, e))?;
+
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?))
+        .map_err(|e| invalid("key", key, e))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key)?))
+            .map_err(|e| invalid("key", key, e))?;
+    }
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => return Err(invalid("key", key, "no private key found")),
+    };
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key);
+    Ok(Arc::new(config))
+}
+
+/// Trusts the certificates in `roots`, or the usual web roots when there is no file.
+pub fn client_config(roots: Option<&Path>) -> io::Result<Arc<ClientConfig>> {
+    let mut config = ClientConfig::new();
+    match roots {
+        Some(path) => {
+            let (added, _) = config.root_store
+                .add_pem_file(&mut BufReader::new(File::open(path)?))
+                .map_err(|e| invalid("certificate", path, e))?;
+            if added == 0 {
+                return Err(invalid("certificate", path, "no certificates found"));
+            }
+        },
+        None => config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    Ok(Arc::new(config))
+}
+
+/// A TLS session over a non-blocking socket. Reads and writes report `WouldBlock` like the
+/// socket would, so it can stand in for the socket under tungstenite.
+pub struct TlsStream<S: Session> {
+    socket: TcpStream,
+    session: S,
+    /// Plaintext taken since the session last had nothing left to send. Some of it may be
+    /// out already, so this is an upper bound on what the session holds.
+    buffered: usize,
+}
+
+impl TlsStream<ServerSession> {
+    pub fn accept(socket: TcpStream, config: &Arc<ServerConfig>) -> TlsStream<ServerSession> {
+        TlsStream {
+            socket: socket,
+            session: ServerSession::new(config),
+            buffered: 0,
+        }
+    }
+}
+
+impl TlsStream<ClientSession> {
+    pub fn connect(
+        socket: TcpStream,
+        config: &Arc<ClientConfig>,
+        domain: &str,
+    ) -> io::Result<TlsStream<ClientSession>> {
+        let domain = DNSNameRef::try_from_ascii_str(domain)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Invalid TLS domain name"))?;
+        Ok(TlsStream {
+            socket: socket,
+            session: ClientSession::new(config, domain),
+            buffered: 0,
+        })
+    }
+}
+
+impl<S: Session> TlsStream<S> {
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    /// Sends as much buffered TLS data as the socket takes.
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.socket)?;
+        }
+        self.buffered = 0;
+        Ok(())
+    }
+}
+
+impl<S: Session> Read for TlsStream<S> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.session.read(buffer)?;
+            if read > 0 {
+                return Ok(read);
+            }
+
+            // the handshake needs replies before any application data arrives
+            match self.write_tls() {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                result => result?,
+            }
+
+            if self.session.read_tls(&mut self.socket)? == 0 {
+                return Ok(0);
+            }
+            self.session
+                .process_new_packets()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+    }
+}
+
+impl<S: Session> Write for TlsStream<S> {
+    /// Data is accepted into the session's buffer, and whatever the socket does not take now
+    /// goes out on a later write or flush. Once `MAX_BUFFERED` is waiting, writes report
+    /// `WouldBlock` until the socket has taken it all.
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        if self.buffered >= MAX_BUFFERED {
+            self.write_tls()?;
+        }
+        let written = self.session.write(buffer)?;
+        self.buffered += written;
+        match self.write_tls() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(written),
+            Err(e) => Err(e),
+            Ok(()) => Ok(written),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.flush()?;
+        self.write_tls()
+    }
+}
+
+/// A connection that may or may not be wrapped in TLS.
+pub enum Stream<S: Session> {
+    Plain(TcpStream),
+    Tls(TlsStream<S>),
+}
+
+pub type ServerStream = Stream<ServerSession>;
+pub type ClientStream = Stream<ClientSession>;
+
+impl<S: Session> Stream<S> {
+    /// The underlying socket, for registering with a `Poll`.
+    pub fn get_ref(&self) -> &TcpStream {
+        match *self {
+            Stream::Plain(ref socket) => socket,
+            Stream::Tls(ref stream) => stream.get_ref(),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().peer_addr()
+    }
+
+    /// Whether TLS still holds data the socket has not taken.
+    pub fn wants_write(&self) -> bool {
+        match *self {
+            Stream::Plain(_) => false,
+            Stream::Tls(ref stream) => stream.session.wants_write(),
+        }
+    }
+}
+
+impl<S: Session> Read for Stream<S> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut socket) => socket.read(buffer),
+            Stream::Tls(ref mut stream) => stream.read(buffer),
+        }
+    }
+}
+
+impl<S: Session> Write for Stream<S> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut socket) => socket.write(buffer),
+            Stream::Tls(ref mut stream) => stream.write(buffer),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut socket) => socket.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,1089 @@
+//! Client connections, sharded across worker threads.
+//!
+//! The main thread owns the upstream link and accepts connections, handing each to a worker
+//! in turn. Every worker runs its own poll loop over its share of the clients. State changes
+//! and vote calls are framed once on the main thread and fanned out to the workers, and each
+//! worker reports a `Tally` of its clients' votes when asked, which the main thread merges
+//! before voting upstream.
+
+use slab::Slab;
+
+use mio::*;
+use mio::net::TcpStream;
+
+use tungstenite::{WebSocket, Message};
+use tungstenite::HandshakeError::{self, Interrupted};
+use tungstenite::handshake::MidHandshake;
+use tungstenite::handshake::server::ServerHandshake;
+use tungstenite::util::NonBlockingError;
+
+use common::{Vote, StateChange, Pong, Hello, Challenge, Answer, ErrorMessage, Node,
+             Message as Protocol, Encoding, RoundTrip, now_millis, codec, token};
+
+use broadcast::{self, Frame, WriteQueue};
+use tls::{ServerConfig, ServerStream, Stream, TlsStream};
+
+use rand::{self, Rng};
+
+use serde_json;
+
+use std::io::{self, ErrorKind, Write};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use limits::{Ballots, DroppedVotes, TokenBucket};
+use mailbox::{Inbox, Mailbox};
+use registry::Children;
+use {Config, Dedupe, decode_frame};
+
+/// Messages from the main thread to a worker.
+pub enum Command {
+    /// A freshly accepted connection, still to complete the WebSocket handshake. Connections
+    /// from the spectator listener are spectators whatever their `Hello` says.
+    Accept { stream: TcpStream, spectator: bool },
+    /// A new state, along with the delta from the previous one for clients that asked for
+    /// deltas.
+    State {
+        state: Arc<StateChange>,
+        full: Frames,
+        delta: Option<Frames>,
+    },
+    VoteCall { round: u64, frames: Frames },
+    /// Our place in the tree, for child funnels to fall back on.
+    Lineage(Frames),
+    /// Asks for a `Report::Tally` of the votes since the last one.
+    Collect,
+}
+
+/// Messages from a worker to the main thread, sent along with the worker's id.
+pub enum Report {
+    Tally(Tally),
+    /// Sent once every ping interval.
+    Clients(ClientMetrics),
+}
+
+/// Frames a message in the given encoding.
+pub fn frame(message: &Protocol, encoding: Encoding) -> Frame {
+    match encoding {
+        Encoding::Json => broadcast::frame(&Message::text(serde_json::to_string(message).unwrap())),
+        Encoding::Binary => broadcast::frame(&Message::binary(codec::encode(message))),
+    }
+}
+
+/// A message framed in every encoding a client may have negotiated.
+#[derive(Clone)]
+pub struct Frames {
+    json: Frame,
+    binary: Option<Frame>,
+}
+
+impl Frames {
+    pub fn new(message: &Protocol, binary: bool) -> Frames {
+        Frames {
+            json: frame(message, Encoding::Json),
+            binary: if binary {
+                Some(frame(message, Encoding::Binary))
+            } else {
+                None
+            },
+        }
+    }
+
+    fn get(&self, encoding: Encoding) -> Frame {
+        match (encoding, &self.binary) {
+            (Encoding::Binary, &Some(ref binary)) => binary.clone(),
+            _ => self.json.clone(),
+        }
+    }
+}
+
+/// The votes one worker collected in a round, in a form that merges with other workers'
+/// tallies without biasing which vote gets picked.
+#[derive(Debug, Default)]
+pub struct Tally {
+    /// The summed weight of every vote, stopping at `u32::MAX`.
+    pub weight: u32,
+    pub voters: usize,
+    /// One of the votes, picked in proportion to its weight. Votes of weight zero are never
+    /// picked.
+    pub pick: Option<Vote>,
+}
+
+impl Tally {
+    fn add(&mut self, vote: Vote) {
+        self.weight = self.weight.saturating_add(vote.weight);
+        self.voters += 1;
+        if vote.weight > 0 && rand::thread_rng().gen_range(0, self.weight) < vote.weight {
+            self.pick = Some(vote);
+        }
+    }
+
+    pub fn merge(&mut self, other: Tally) {
+        self.weight = self.weight.saturating_add(other.weight);
+        self.voters += other.voters;
+        if other.weight > 0 && rand::thread_rng().gen_range(0, self.weight) < other.weight {
+            self.pick = other.pick;
+        }
+    }
+}
+
+/// Client round trips on one worker, in milliseconds.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClientMetrics {
+    /// Every client, spectators and child funnels included.
+    pub clients: usize,
+    pub spectators: usize,
+    pub funnels: usize,
+    pub round_trip_min: Option<u64>,
+    pub round_trip_max: Option<u64>,
+    pub round_trip_sum: u64,
+    /// How many clients have a measured round trip.
+    pub round_trip_count: u64,
+    /// Totals since the worker started.
+    pub dropped: DroppedVotes,
+    /// Clients cut off for going over their rate limit, since the worker started.
+    pub disconnected: u64,
+    /// Clients cut off for falling `max_queued_bytes` behind, since the worker started.
+    pub lagging: u64,
+}
+
+impl ClientMetrics {
+    pub fn merge(&mut self, other: &ClientMetrics) {
+        self.clients += other.clients;
+        self.spectators += other.spectators;
+        self.funnels += other.funnels;
+        self.round_trip_min = match (self.round_trip_min, other.round_trip_min) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.round_trip_max = self.round_trip_max.max(other.round_trip_max);
+        self.round_trip_sum += other.round_trip_sum;
+        self.round_trip_count += other.round_trip_count;
+        self.dropped.merge(&other.dropped);
+        self.disconnected += other.disconnected;
+        self.lagging += other.lagging;
+    }
+
+    pub fn round_trip_mean(&self) -> Option<u64> {
+        if self.round_trip_count == 0 {
+            None
+        } else {
+            Some(self.round_trip_sum / self.round_trip_count)
+        }
+    }
+}
+
+/// A connection still in its WebSocket handshake, and its TLS one when serving TLS.
+struct Handshake {
+    /// Taken while the handshake is being taken further.
+    in_progress: Option<MidHandshake<ServerHandshake<ServerStream>>>,
+    spectator: bool,
+    deadline: Instant,
+}
+
+struct Client {
+    vote: Option<Vote>,
+    /// The voter whose ballot `vote` holds, when votes are deduplicated.
+    ballot: Option<String>,
+    websocket: WebSocket<ServerStream>,
+    address: Option<IpAddr>,
+    round_trip: RoundTrip,
+    /// Set once the client's `Hello` has been accepted.
+    welcomed: bool,
+    encoding: Encoding,
+    /// Whether the client asked for `StateDelta`s rather than full states.
+    deltas: bool,
+    /// Spectators get everything other clients do, but their votes are ignored.
+    spectator: bool,
+    /// Who the client's token says it is, or the id of a child funnel. Always `None` for end
+    /// users when authentication is off.
+    identity: Option<String>,
+    /// Set once a child funnel has answered its challenge. Only these clients may send
+    /// weighted votes.
+    funnel: Option<String>,
+    /// The `Hello` of a child funnel still to answer its challenge, and the challenge.
+    challenge: Option<(Hello, String)>,
+    /// Everything we send goes through here rather than `websocket.write_message`, so that
+    /// broadcast frames are shared instead of copied per client.
+    queue: WriteQueue,
+    bucket: Option<TokenBucket>,
+    /// Messages dropped in a row for going over the rate limit.
+    strikes: u32,
+    /// Set once the client has been turned away. It is dropped when its queue has been
+    /// written, or at the next ping if it never is.
+    closing: bool,
+}
+
+impl Client {
+    fn new(
+        socket: WebSocket<ServerStream>,
+        spectator: bool,
+        bucket: Option<TokenBucket>,
+    ) -> Client {
+        let address = socket.get_ref().peer_addr().ok().map(|address| address.ip());
+        Client {
+            vote: None,
+            ballot: None,
+            websocket: socket,
+            address: address,
+            round_trip: RoundTrip::new(),
+            welcomed: false,
+            encoding: Encoding::Json,
+            deltas: false,
+            spectator: spectator,
+            identity: None,
+            funnel: None,
+            challenge: None,
+            queue: WriteQueue::new(),
+            bucket: bucket,
+            strikes: 0,
+            closing: false,
+        }
+    }
+
+    /// Who the client votes as, if votes are deduplicated.
+    fn voter(&self, dedupe: Dedupe) -> Option<String> {
+        let address = self.address.map(|address| format!("ip:{}", address));
+        match dedupe {
+            Dedupe::Off => None,
+            Dedupe::Ip => address,
+            Dedupe::Identity => {
+                self.identity.as_ref().map(|identity| format!("id:{}", identity)).or(address)
+            },
+        }
+    }
+}
+
+/// WebSocket ping payloads carry the time they were sent, so that the matching pong gives
+/// the round trip without keeping any state per ping.
+fn encode_timestamp(time: u64) -> Vec<u8> {
+    (0..8).map(|i| (time >> (56 - 8 * i)) as u8).collect()
+}
+
+fn decode_timestamp(payload: &[u8]) -> Option<u64> {
+    if payload.len() != 8 {
+        return None;
+    }
+    Some(payload.iter().fold(0, |time, &byte| time << 8 | byte as u64))
+}
+
+/// How long a new connection has to finish its handshake before it is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const COMMANDS: Token = Token(0);
+const FIRST_CLIENT: Token = Token(1);
+
+// clients take the even tokens from `FIRST_CLIENT` on, and handshakes the odd ones
+fn client_conn_token(index: usize) -> Token {
+    Token(FIRST_CLIENT.0 + 2 * index)
+}
+
+fn client_conn_untoken(token: Token) -> usize {
+    (token.0 - FIRST_CLIENT.0) / 2
+}
+
+fn handshake_token(index: usize) -> Token {
+    Token(FIRST_CLIENT.0 + 2 * index + 1)
+}
+
+fn is_handshake_token(token: Token) -> bool {
+    (token.0 - FIRST_CLIENT.0) % 2 == 1
+}
+
+pub struct Worker {
+    id: usize,
+    config: Config,
+    tls: Option<Arc<ServerConfig>>,
+    poll: Poll,
+    commands: Inbox<Command>,
+    reports: Mailbox<(usize, Report)>,
+    clients: Slab<Client>,
+    handshakes: Slab<Handshake>,
+    ballots: Ballots,
+    children: Children,
+    latest_state: Option<Arc<StateChange>>,
+    lineage: Option<Frames>,
+    round: Option<u64>,
+    next_ping: Instant,
+    dropped: DroppedVotes,
+    disconnected: u64,
+    lagging: u64,
+}
+
+impl Worker {
+    pub fn new(
+        id: usize,
+        config: Config,
+        tls: Option<Arc<ServerConfig>>,
+        commands: Inbox<Command>,
+        reports: Mailbox<(usize, Report)>,
+        ballots: Ballots,
+        children: Children,
+    ) -> Worker {
+        let poll = Poll::new().unwrap();
+        commands.register(&poll, COMMANDS).unwrap();
+
+        Worker {
+            id: id,
+            config: config,
+            tls: tls,
+            poll: poll,
+            commands: commands,
+            reports: reports,
+            clients: Slab::new(),
+            handshakes: Slab::new(),
+            ballots: ballots,
+            children: children,
+            latest_state: None,
+            lineage: None,
+            round: None,
+            next_ping: Instant::now(),
+            dropped: DroppedVotes::default(),
+            disconnected: 0,
+            lagging: 0,
+        }
+    }
+
+    pub fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            let time = Instant::now();
+            if time >= self.next_ping {
+                self.ping_clients();
+                self.report_metrics();
+                self.next_ping = time + self.config.ping_interval;
+            }
+
+            let mut wake = self.next_ping;
+            if let Some(deadline) = self.expire_handshakes(time) {
+                wake = wake.min(deadline);
+            }
+            self.poll.poll(&mut events, Some(wake - time)).unwrap();
+
+            for event in &events {
+                match event.token() {
+                    COMMANDS => {
+                        for command in self.commands.drain() {
+                            self.command(command);
+                        }
+                    },
+                    token if is_handshake_token(token) => {
+                        let index = client_conn_untoken(token);
+                        if self.handshakes.contains(index) {
+                            self.handshake_event(index);
+                        }
+                    },
+                    client => {
+                        // an earlier event may have disconnected the client
+                        let index = client_conn_untoken(client);
+                        if event.readiness().is_readable() && self.clients.contains(index) {
+                            let _ = self.client_readable_event(&event);
+                        }
+                        if event.readiness().is_writable() && self.clients.contains(index) {
+                            let _ = self.client_writable_event(&event);
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, command: Command) {
+        match command {
+            Command::Accept { stream, spectator } => {
+                if let Err(e) = self.new_client(stream, spectator) {
+                    warn!("Client accept failed: {:?}", e);
+                }
+            },
+            Command::State { state, full, delta } => {
+                self.latest_state = Some(state);
+                self.broadcast(&full, delta.as_ref());
+            },
+            Command::VoteCall { round, frames } => {
+                // nothing from an earlier round may count towards this one
+                self.take_votes();
+                self.round = Some(round);
+                self.broadcast(&frames, None);
+            },
+            Command::Lineage(frames) => {
+                let mut funnels = Vec::new();
+                for (index, client) in &self.clients {
+                    if client.welcomed && client.funnel.is_some() {
+                        funnels.push((index, client.encoding));
+                    }
+                }
+                for &(index, encoding) in &funnels {
+                    let _ = self.send_client_frame(index, frames.get(encoding));
+                }
+                self.lineage = Some(frames);
+            },
+            Command::Collect => {
+                // the round stays closed until the next vote call
+                self.round = None;
+                let tally = self.take_votes();
+                info!("Worker {} collected {} votes", self.id, tally.voters);
+                let _ = self.reports.send((self.id, Report::Tally(tally)));
+            },
+        }
+    }
+
+    /// Takes every client's vote, giving up the ballots they hold.
+    fn take_votes(&mut self) -> Tally {
+        let mut tally = Tally::default();
+        for (index, client) in &mut self.clients {
+            if let Some(vote) = client.vote.take() {
+                tally.add(vote);
+            }
+            if let Some(voter) = client.ballot.take() {
+                self.ballots.release(&voter, (self.id, index));
+            }
+        }
+        tally
+    }
+
+    /// Starts the handshake of a new connection, which goes on as its socket is ready.
+    fn new_client(&mut self, stream: TcpStream, spectator: bool) -> io::Result<()> {
+        let entry = self.handshakes.vacant_entry();
+        self.poll.register(
+            &stream,
+            handshake_token(entry.key()),
+            Ready::readable() | Ready::writable(),
+            PollOpt::edge()
+            )?;
+
+        let stream = match self.tls {
+            Some(ref tls) => Stream::Tls(TlsStream::accept(stream, tls)),
+            None => Stream::Plain(stream),
+        };
+        entry.insert(Handshake {
+            in_progress: Some(ServerHandshake::start(stream, None)),
+            spectator: spectator,
+            deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+        });
+        Ok(())
+    }
+
+    /// Takes a connection's handshake as far as its socket allows, and makes it a client once
+    /// the handshake is done.
+    fn handshake_event(&mut self, index: usize) {
+        let in_progress = self.handshakes.get_mut(index).unwrap().in_progress.take().unwrap();
+        match in_progress.handshake() {
+            Ok(websocket) => {
+                let spectator = self.handshakes.remove(index).spectator;
+                if let Err(e) = self.open_client(websocket, spectator) {
+                    warn!("Client accept failed: {:?}", e);
+                }
+            },
+            Err(Interrupted(in_progress)) => {
+                self.handshakes.get_mut(index).unwrap().in_progress = Some(in_progress);
+            },
+            Err(HandshakeError::Failure(e)) => {
+                warn!("Client handshake failed: {}", e);
+                self.handshakes.remove(index);
+            },
+        }
+    }
+
+    /// Drops the connections whose handshake has run out of time, returning when the next
+    /// one will.
+    fn expire_handshakes(&mut self, now: Instant) -> Option<Instant> {
+        let expired: Vec<usize> = self.handshakes
+            .iter()
+            .filter(|&(_, handshake)| handshake.deadline <= now)
+            .map(|(index, _)| index)
+            .collect();
+        for index in expired {
+            info!("Dropping connection that did not finish its handshake in {:?}",
+                  HANDSHAKE_TIMEOUT);
+            self.handshakes.remove(index);
+        }
+        self.handshakes.iter().map(|(_, handshake)| handshake.deadline).min()
+    }
+
+    fn open_client(
+        &mut self,
+        websocket: WebSocket<ServerStream>,
+        spectator: bool,
+    ) -> Result<(), ::tungstenite::error::Error> {
+        let bucket = self.config.rate_limit.as_ref()
+            .map(|limit| TokenBucket::new(limit.messages_per_second, limit.burst));
+        let client = Client::new(websocket, spectator, bucket);
+
+        let index = self.clients.insert(client);
+        {
+            let client = self.clients.get_mut(index).unwrap();
+            self.poll.reregister(
+                client.websocket.get_ref().get_ref(),
+                client_conn_token(index),
+                Ready::readable() | Ready::writable(),
+                PollOpt::edge()
+                )?;
+
+            info!("Connection established on worker {}: {}",
+                     self.id, client.websocket.get_ref().peer_addr()?);
+        }
+
+        // the handshake may have read past its end, where no readable event will point
+        self.read_client(index)
+    }
+
+    /// Sends frames to every welcomed client, sending `delta` instead to the clients that
+    /// asked for deltas when there is one.
+    fn broadcast(&mut self, full: &Frames, delta: Option<&Frames>) {
+        let mut clients = Vec::new();
+        for (index, client) in &self.clients {
+            if client.welcomed {
+                clients.push((index, client.encoding, client.deltas));
+            }
+        }
+        for &(index, encoding, deltas) in &clients {
+            let frame = match delta {
+                Some(delta) if deltas => delta.get(encoding),
+                _ => full.get(encoding),
+            };
+            let _ = self.send_client_frame(index, frame);
+        }
+    }
+
+    fn client_readable_event(&mut self, event: &Event) -> Result<(), ::tungstenite::error::Error> {
+        let index = client_conn_untoken(event.token());
+
+        // tungstenite writes its own replies to pings and closes while reading, which must not
+        // land in the middle of one of our frames. The writable event that finishes the frame
+        // picks the reading back up.
+        let client = self.clients.get(index).unwrap();
+        if client.queue.in_frame() || client.closing {
+            return Ok(());
+        }
+
+        self.read_client(index)
+    }
+
+    fn read_client(&mut self, index: usize) -> Result<(), ::tungstenite::error::Error> {
+        loop {
+            let message;
+            {
+                let client = self.clients.get_mut(index).unwrap();
+                message = client.websocket.read_message();
+            }
+            match message {
+                Ok(message) => self.client_message(index, message),
+                Err(e) => match e.into_non_blocking() {
+                    None => return Ok(()),
+                    Some(e) => {
+                        self.remove_client(index);
+                        return Err(e);
+                    },
+                },
+            }
+            if self.clients.get(index).map_or(true, |client| client.closing) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn client_message(&mut self, index: usize, message: Message) {
+        let encoding = self.clients.get(index).unwrap().encoding;
+        let message: Protocol = match message {
+            Message::Pong(payload) => {
+                if let Some(sent) = decode_timestamp(&payload) {
+                    let client = self.clients.get_mut(index).unwrap();
+                    client.round_trip.record(now_millis().saturating_sub(sent));
+                    debug!("Client #{} round trip {:?} ms", index, client.round_trip.smoothed());
+                }
+                return;
+            },
+            _ if !self.within_rate_limit(index) => return,
+            frame => match decode_frame(frame, encoding) {
+                Some(Ok(decoded)) => {
+                    info!("Received from client: {:?}", decoded);
+                    decoded
+                },
+                Some(Err(e)) => {
+                    warn!("Badly formatted message received from client: {}", e);
+                    return;
+                },
+                None => return,
+            },
+        };
+
+        let welcomed = self.clients.get(index).unwrap().welcomed;
+        let funnel = self.clients.get(index).unwrap().funnel.is_some();
+        let vote = match message {
+            // a second hello could change who the client is after it has voted
+            Protocol::Hello(_) if welcomed => {
+                warn!("Client #{} sent hello again", index);
+                let error = ErrorMessage { error: "already welcomed".to_owned() };
+                let _ = self.send_client(index, &Protocol::Error(error));
+                return;
+            },
+            Protocol::Hello(hello) => {
+                self.client_hello(index, hello);
+                return;
+            },
+            Protocol::Answer(answer) if !welcomed => {
+                self.client_answer(index, answer);
+                return;
+            },
+            // a child funnel pings from when it connects, which may be before we welcome it
+            Protocol::Ping(ping) => {
+                let _ = self.send_client(index, &Protocol::Pong(Pong::reply(&ping)));
+                return;
+            },
+            _ if !welcomed => {
+                warn!("Client #{} sent {:?} before hello", index, message);
+                let error = ErrorMessage { error: "expected hello".to_owned() };
+                let _ = self.send_client(index, &Protocol::Error(error));
+                return;
+            },
+            Protocol::Ballot(ballot) => Vote {
+                action: ballot.action,
+                weight: 1,
+                round: Some(ballot.round),
+            },
+            Protocol::Vote(vote) if funnel => vote,
+            Protocol::Vote(_) => {
+                warn!("Dropping weighted vote from client #{}, which is not a trusted funnel",
+                      index);
+                self.dropped.untrusted_weight += 1;
+                let error = ErrorMessage {
+                    error: "only trusted funnels may send weighted votes".to_owned(),
+                };
+                let _ = self.send_client(index, &Protocol::Error(error));
+                return;
+            },
+            Protocol::Resync => {
+                let state = self.latest_state.clone();
+                if let Some(state) = state {
+                    let _ = self.send_client(index, &Protocol::StateChange((*state).clone()));
+                }
+                return;
+            },
+            Protocol::Topology(node) => {
+                self.client_topology(index, node);
+                return;
+            },
+            message => {
+                warn!("Unexpected message from client #{}: {:?}", index, message);
+                return;
+            },
+        };
+
+        if self.clients.get(index).unwrap().spectator {
+            debug!("Ignoring vote from spectator #{}", index);
+            self.dropped.spectator += 1;
+            return;
+        }
+
+        if self.config.auth.is_some() && self.clients.get(index).unwrap().identity.is_none() {
+            warn!("Dropping vote from unauthenticated client #{}", index);
+            self.dropped.unauthenticated += 1;
+            let error = ErrorMessage { error: "authentication required to vote".to_owned() };
+            let _ = self.send_client(index, &Protocol::Error(error));
+            return;
+        }
+
+        // votes have to name the round that is open, and none is open between collecting
+        // the votes and the next vote call
+        if self.round.is_none() || vote.round != self.round {
+            warn!("Dropping vote for round {:?} during round {:?}", vote.round, self.round);
+            self.dropped.wrong_round += 1;
+            return;
+        }
+
+        // a funnel's vote already stands for many voters
+        let voter = if funnel {
+            None
+        } else {
+            self.clients.get(index).unwrap().voter(self.config.dedupe_votes)
+        };
+        if let Some(ref voter) = voter {
+            if !self.ballots.claim(voter, (self.id, index)) {
+                debug!("Dropping duplicate vote from {} on client #{}", voter, index);
+                self.dropped.duplicate += 1;
+                let error = ErrorMessage { error: "already voted this round".to_owned() };
+                let _ = self.send_client(index, &Protocol::Error(error));
+                return;
+            }
+        }
+
+        let client = self.clients.get_mut(index).unwrap();
+        client.ballot = voter;
+        if let Some(ref identity) = client.identity {
+            info!(target: "audit", "{} voted {:?}", identity, vote.action);
+        }
+        client.vote = Some(vote);
+    }
+
+    fn client_writable_event(&mut self, event: &Event) -> Result<(), ::tungstenite::error::Error> {
+        let index = client_conn_untoken(event.token());
+
+        if let Err(e) = self.flush_client(index) {
+            self.remove_client(index);
+            return Err(e);
+        }
+
+        let client = self.clients.get(index).unwrap();
+        if client.closing {
+            self.close_if_sent(index);
+            Ok(())
+        } else if client.queue.in_frame() {
+            Ok(())
+        } else {
+            self.read_client(index)
+        }
+    }
+
+    /// Answers a client's `Hello`. Child funnels are challenged to prove who they are first,
+    /// and everyone else has their token checked.
+    fn client_hello(&mut self, index: usize, hello: Hello) {
+        if let Some(funnel) = hello.funnel.clone() {
+            if self.children.key(&funnel).is_none() {
+                let error = ErrorMessage { error: format!("unknown funnel {}", funnel) };
+                return self.reject_client(index, error);
+            }
+            let nonce = token::challenge();
+            self.clients.get_mut(index).unwrap().challenge = Some((hello, nonce.clone()));
+            let _ = self.send_client(index, &Protocol::Challenge(Challenge { nonce: nonce }));
+            return;
+        }
+
+        match self.authenticate(index, &hello) {
+            Ok(identity) => self.welcome_client(index, hello, identity),
+            Err(error) => self.reject_client(index, ErrorMessage { error: error }),
+        }
+    }
+
+    /// Checks a child funnel's answer to its challenge, registering it as connected if the
+    /// answer is right.
+    fn client_answer(&mut self, index: usize, answer: Answer) {
+        let (hello, nonce) = match self.clients.get_mut(index).unwrap().challenge.take() {
+            Some(challenge) => challenge,
+            None => {
+                warn!("Client #{} answered a challenge it was not sent", index);
+                return;
+            },
+        };
+        let funnel = hello.funnel.clone().unwrap();
+
+        let answered = self.children
+            .key(&funnel)
+            .map_or(false, |key| token::check_answer(key.as_bytes(), &nonce, &answer.signature));
+        if !answered {
+            info!(target: "audit", "Client #{} on worker {} failed to prove it is funnel {}",
+                  index, self.id, funnel);
+            let error = ErrorMessage { error: format!("wrong answer for funnel {}", funnel) };
+            return self.reject_client(index, error);
+        }
+        if let Err(refusal) = self.children.connect(&funnel, (self.id, index)) {
+            let error = ErrorMessage { error: format!("funnel {} refused: {}", funnel, refusal) };
+            return self.reject_client(index, error);
+        }
+
+        info!(target: "audit", "Client #{} on worker {} is funnel {}", index, self.id, funnel);
+        self.clients.get_mut(index).unwrap().funnel = Some(funnel.clone());
+        if let Some(node) = hello.topology.clone() {
+            self.client_topology(index, node);
+        }
+        self.welcome_client(index, hello, Some(funnel));
+    }
+
+    /// Keeps a child funnel's report of its subtree, under the id it registered with.
+    fn client_topology(&mut self, index: usize, mut node: Node) {
+        let funnel = match self.clients.get(index).unwrap().funnel {
+            Some(ref funnel) => funnel.clone(),
+            None => {
+                warn!("Ignoring topology from client #{}, which is not a child funnel", index);
+                return;
+            },
+        };
+        node.id = funnel.clone();
+        self.children.report(&funnel, (self.id, index), node);
+    }
+
+    /// Catches the client up on the game if it speaks our protocol, and disconnects it
+    /// otherwise.
+    fn welcome_client(&mut self, index: usize, hello: Hello, identity: Option<String>) {
+        let support: &[Encoding] = if self.config.binary_encoding {
+            &[Encoding::Binary]
+        } else {
+            &[]
+        };
+        match hello.accept(support) {
+            Ok(welcome) => {
+                if self.send_client(index, &Protocol::Welcome(welcome)).is_err() {
+                    return;
+                }
+                let client = self.clients.get_mut(index).unwrap();
+                client.welcomed = true;
+                client.encoding = welcome.encoding;
+                client.deltas = hello.deltas;
+                client.spectator |= hello.spectator;
+                client.identity = identity;
+
+                let funnel = client.funnel.is_some();
+
+                let state = self.latest_state.clone();
+                if let Some(state) = state {
+                    let _ = self.send_client(index, &Protocol::StateChange((*state).clone()));
+                }
+                let lineage = self.lineage.as_ref().map(|frames| frames.get(welcome.encoding));
+                if let (true, Some(lineage)) = (funnel, lineage) {
+                    let _ = self.send_client_frame(index, lineage);
+                }
+            },
+            Err(error) => self.reject_client(index, error),
+        }
+    }
+
+    /// Sends the client an error and closes its connection once the error is out.
+    fn reject_client(&mut self, index: usize, error: ErrorMessage) {
+        warn!("Rejected client #{}: {}", index, error.error);
+        if self.send_client(index, &Protocol::Error(error)).is_ok() {
+            self.clients.get_mut(index).unwrap().closing = true;
+            self.close_if_sent(index);
+        }
+    }
+
+    /// Drops a closing client once everything queued for it has reached the socket.
+    fn close_if_sent(&mut self, index: usize) {
+        let sent = {
+            let client = self.clients.get(index).unwrap();
+            client.queue.is_empty() && !client.websocket.get_ref().wants_write()
+        };
+        if sent {
+            self.remove_client(index);
+        }
+    }
+
+    /// Checks the token in a client's hello, returning who it belongs to. Clients without a
+    /// valid token are turned away unless the config lets them in to watch.
+    fn authenticate(&self, index: usize, hello: &Hello) -> Result<Option<String>, String> {
+        let auth = match self.config.auth {
+            Some(ref auth) => auth,
+            None => return Ok(None),
+        };
+
+        let result = match hello.token {
+            Some(ref hello_token) => {
+                token::verify(auth.secret.as_bytes(), hello_token, now_millis())
+                    .map_err(|e| e.to_string())
+            },
+            None => Err("token required".to_owned()),
+        };
+
+        match result {
+            Ok(identity) => {
+                info!(target: "audit", "Client #{} on worker {} is {}", index, self.id, identity);
+                Ok(Some(identity))
+            },
+            Err(error) => {
+                info!(target: "audit", "Client #{} on worker {} failed authentication: {}",
+                      index, self.id, error);
+                if auth.allow_unauthenticated {
+                    Ok(None)
+                } else {
+                    Err(error)
+                }
+            },
+        }
+    }
+
+    /// Spends one of the client's tokens on a message, disconnecting it once it has gone too
+    /// many messages in a row without one.
+    fn within_rate_limit(&mut self, index: usize) -> bool {
+        let limit = match self.config.rate_limit {
+            Some(ref limit) => limit,
+            None => return true,
+        };
+
+        {
+            let client = self.clients.get_mut(index).unwrap();
+            let allowed = match client.bucket {
+                Some(ref mut bucket) => bucket.take(Instant::now()),
+                None => true,
+            };
+            self.dropped.rate_limited += if allowed { 0 } else { 1 };
+            if allowed {
+                client.strikes = 0;
+                return true;
+            }
+            client.strikes += 1;
+            if client.strikes < limit.disconnect_after {
+                return false;
+            }
+        }
+
+        warn!("Disconnecting client #{} on worker {} for going over its rate limit",
+              index, self.id);
+        self.disconnected += 1;
+        self.remove_client(index);
+        false
+    }
+
+    /// Drops a client, giving up any ballot it holds.
+    fn remove_client(&mut self, index: usize) {
+        let client = self.clients.remove(index);
+        if let Some(voter) = client.ballot {
+            self.ballots.release(&voter, (self.id, index));
+        }
+        if let Some(funnel) = client.funnel {
+            info!("Child funnel {} disconnected", funnel);
+            self.children.disconnect(&funnel, (self.id, index));
+        }
+    }
+
+    fn send_client(&mut self, index: usize, message: &Protocol) -> ::std::io::Result<()> {
+        let encoding = match self.clients.get(index) {
+            Some(client) => client.encoding,
+            None => return Err(ErrorKind::NotConnected.into()),
+        };
+        self.send_client_frame(index, frame(message, encoding))
+    }
+
+    /// Queues a frame for the client and sends what the socket takes. A client whose socket
+    /// fails, or who has more than `max_queued_bytes` waiting, is removed, and the error
+    /// returned so callers know it is gone.
+    fn send_client_frame(&mut self, index: usize, frame: Frame) -> ::std::io::Result<()> {
+        match self.clients.get_mut(index) {
+            Some(client) => client.queue.push(frame),
+            None => return Err(ErrorKind::NotConnected.into()),
+        }
+
+        if let Err(e) = self.flush_client(index) {
+            warn!("Error sending to websocket: {:?}", e);
+            self.remove_client(index);
+            return Err(ErrorKind::NotConnected.into());
+        }
+
+        let queued = self.clients.get(index).unwrap().queue.bytes();
+        if queued > self.config.max_queued_bytes {
+            warn!("Disconnecting client #{} on worker {}, which has {} bytes waiting",
+                  index, self.id, queued);
+            self.lagging += 1;
+            self.remove_client(index);
+            return Err(ErrorKind::NotConnected.into());
+        }
+
+        Ok(())
+    }
+
+    /// Writes whatever tungstenite has pending and then as much of the client's queue as the
+    /// socket takes. The rest goes out on the next writable event.
+    fn flush_client(&mut self, index: usize) -> Result<(), ::tungstenite::error::Error> {
+        let client = self.clients.get_mut(index).unwrap();
+
+        if !client.queue.in_frame() {
+            if let Err(e) = client.websocket.write_pending() {
+                return match e.into_non_blocking() {
+                    None => Ok(()),
+                    Some(e) => Err(e),
+                };
+            }
+        }
+
+        client.queue.flush(client.websocket.get_mut())?;
+
+        // sends on whatever TLS buffered when the socket was full
+        match client.websocket.get_mut().flush() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    fn ping_clients(&mut self) {
+        let frame = broadcast::frame(&Message::Ping(encode_timestamp(now_millis())));
+        let mut clients = Vec::new();
+        let mut closing = Vec::new();
+        for (index, client) in &self.clients {
+            if client.closing {
+                closing.push(index);
+            } else {
+                clients.push(index);
+            }
+        }
+        // a turned away client has had a whole ping interval to take its error
+        for &index in &closing {
+            self.remove_client(index);
+        }
+        for &index in &clients {
+            let _ = self.send_client_frame(index, frame.clone());
+        }
+    }
+
+    fn report_metrics(&self) {
+        let mut metrics = ClientMetrics::default();
+        metrics.clients = self.clients.len();
+        metrics.dropped = self.dropped;
+        metrics.disconnected = self.disconnected;
+        metrics.lagging = self.lagging;
+        for (_, client) in &self.clients {
+            if client.spectator {
+                metrics.spectators += 1;
+            }
+            if client.funnel.is_some() {
+                metrics.funnels += 1;
+            }
+            if let Some(round_trip) = client.round_trip.smoothed() {
+                metrics.merge(&ClientMetrics {
+                    clients: 0,
+                    spectators: 0,
+                    funnels: 0,
+                    round_trip_min: Some(round_trip),
+                    round_trip_max: Some(round_trip),
+                    round_trip_sum: round_trip,
+                    round_trip_count: 1,
+                    dropped: DroppedVotes::default(),
+                    disconnected: 0,
+                    lagging: 0,
+                });
+            }
+        }
+        let _ = self.reports.send((self.id, Report::Clients(metrics)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Action;
+
+    fn vote(from: (u8, u8), weight: u32) -> Vote {
+        Vote { action: Action { from: from, to: (0, 0) }, weight: weight, round: None }
+    }
+
+    #[test]
+    fn picks_in_proportion_to_weight() {
+        let mut heavy = 0;
+        for _ in 0..1000 {
+            let mut tally = Tally::default();
+            tally.add(vote((1, 1), 1));
+            let mut other = Tally::default();
+            other.add(vote((2, 2), 9));
+            other.add(vote((3, 3), 0));
+            tally.merge(other);
+            assert_eq!((tally.weight, tally.voters), (10, 3));
+            match tally.pick.unwrap().action.from {
+                (2, 2) => heavy += 1,
+                (1, 1) => {},
+                from => panic!("picked the weightless vote from {:?}", from),
+            }
+        }
+        // nine in ten, give or take
+        assert!(heavy > 800 && heavy < 980, "heavy vote picked {} times in 1000", heavy);
+    }
+
+    #[test]
+    fn weight_stops_at_the_maximum() {
+        let mut tally = Tally::default();
+        tally.add(vote((1, 1), u32::max_value()));
+        tally.add(vote((2, 2), 1));
+        let mut other = Tally::default();
+        other.add(vote((3, 3), u32::max_value()));
+        tally.merge(other);
+        assert_eq!((tally.weight, tally.voters), (u32::max_value(), 3));
+        assert!(tally.pick.is_some());
+    }
+}
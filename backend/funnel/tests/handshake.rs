@@ -0,0 +1,89 @@
+//! Connections that stall in their handshake: they must not hold up the worker, and they are
+//! dropped once their time runs out.
+
+extern crate common;
+extern crate funnel;
+extern crate serde_json;
+extern crate tungstenite;
+extern crate url;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{Hello, Message};
+
+fn free_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Starts a single-worker funnel on `host`, returning its upstream listener to keep it alive.
+fn start_funnel(host: SocketAddr) -> TcpListener {
+    let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let path = std::env::temp_dir().join("funnel-handshake.toml");
+    let mut file = File::create(&path).unwrap();
+    write!(
+        file,
+        "host = \"{}\"\nupstream = \"{}\"\nupstream_is_websocket = false\nworkers = 1\n",
+        host,
+        upstream.local_addr().unwrap()
+    ).unwrap();
+    let config = funnel::read_config(&path);
+    thread::spawn(move || funnel::run(config));
+    upstream
+}
+
+fn connect(address: SocketAddr) -> TcpStream {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match TcpStream::connect(address) {
+            Ok(stream) => return stream,
+            Err(e) => {
+                assert!(Instant::now() < deadline, "funnel never listened: {}", e);
+                thread::sleep(Duration::from_millis(50));
+            },
+        }
+    }
+}
+
+#[test]
+fn stalled_handshakes_do_not_block_clients() {
+    let host = free_address();
+    let _upstream = start_funnel(host);
+
+    let mut silent = connect(host);
+    let mut partial = connect(host);
+    partial.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+    // a well-behaved client is welcomed while the others stall
+    let stream = connect(host);
+    stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let url = url::Url::parse(&format!("ws://{}", host)).unwrap();
+    let (mut websocket, _) = tungstenite::client(url, stream).ok().unwrap();
+    let hello = serde_json::to_string(&Message::Hello(Hello::new(Vec::new(), false))).unwrap();
+    websocket.write_message(tungstenite::Message::Text(hello)).unwrap();
+    loop {
+        if let tungstenite::Message::Text(text) = websocket.read_message().unwrap() {
+            if let Message::Welcome(_) = serde_json::from_str(&text).unwrap() {
+                break;
+            }
+        }
+    }
+
+    // and the stalled ones are hung up on
+    for stream in &mut [&mut silent, &mut partial] {
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut buffer = [0; 256];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(_) => {},
+                Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionReset => break,
+                Err(e) => panic!("stalled handshake was not dropped: {}", e),
+            }
+        }
+    }
+}
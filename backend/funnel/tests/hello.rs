@@ -0,0 +1,190 @@
+//! A welcomed client keeps the identity it was welcomed with: a second hello can't trade it
+//! for another one to vote again. A client that is turned away hears why before it is cut off.
+
+extern crate common;
+extern crate funnel;
+extern crate serde_json;
+extern crate tungstenite;
+extern crate url;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use common::{token, Action, Ballot, Encoding, Hello, Message, Pong, VoteCall, Welcome};
+use tungstenite::WebSocket;
+
+const SECRET: &str = "hello-test-secret";
+
+fn free_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// The engine's end of the funnel's link.
+struct Engine {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Engine {
+    /// Starts a funnel on `host` that checks tokens and counts one vote per identity, beneath
+    /// a new engine link, and welcomes it.
+    fn start(name: &str, host: SocketAddr) -> Engine {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = std::env::temp_dir().join(format!("funnel-hello-{}.toml", name));
+        let mut file = File::create(&path).unwrap();
+        write!(
+            file,
+            "host = \"{}\"\nupstream = \"{}\"\nupstream_is_websocket = false\nworkers = 1\n\
+             dedupe_votes = \"identity\"\n\
+             timeout_change = {{ secs = 0, nanos = 200000000 }}\n\
+             [auth]\nsecret = \"{}\"\n",
+            host,
+            listener.local_addr().unwrap(),
+            SECRET
+        ).unwrap();
+        let config = funnel::read_config(&path);
+        thread::spawn(move || funnel::run(config));
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut engine = Engine {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream: stream,
+        };
+        match engine.receive() {
+            Message::Hello(_) => {},
+            message => panic!("expected hello, got {:?}", message),
+        }
+        engine.send(&Message::Welcome(Welcome {
+            version: common::PROTOCOL_VERSION,
+            encoding: Encoding::Json,
+        }));
+        engine
+    }
+
+    fn send(&mut self, message: &Message) {
+        let mut line = serde_json::to_string(message).unwrap();
+        line.push('\n');
+        self.stream.write_all(line.as_bytes()).unwrap();
+    }
+
+    /// Reads the next message, answering pings on the way so the funnel keeps the link.
+    fn receive(&mut self) -> Message {
+        loop {
+            let mut line = String::new();
+            assert!(self.reader.read_line(&mut line).unwrap() > 0, "funnel hung up");
+            match serde_json::from_str(&line).unwrap() {
+                Message::Ping(ping) => self.send(&Message::Pong(Pong::reply(&ping))),
+                message => return message,
+            }
+        }
+    }
+}
+
+fn hello(identity: &str) -> String {
+    let mut hello = Hello::new(Vec::new(), false);
+    hello.token = Some(token::sign(SECRET.as_bytes(), identity, common::now_millis() + 60_000));
+    serde_json::to_string(&Message::Hello(hello)).unwrap()
+}
+
+fn connect_client(address: SocketAddr, identity: &str) -> WebSocket<TcpStream> {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let url = url::Url::parse(&format!("ws://{}", address)).unwrap();
+    let (mut websocket, _) = tungstenite::client(url, stream).ok().unwrap();
+    send_client(&mut websocket, &hello(identity));
+    match receive_client(&mut websocket) {
+        Message::Welcome(_) => websocket,
+        message => panic!("expected welcome, got {:?}", message),
+    }
+}
+
+fn send_client(websocket: &mut WebSocket<TcpStream>, text: &str) {
+    websocket.write_message(tungstenite::Message::Text(text.to_owned())).unwrap();
+}
+
+/// Reads the client's next message that isn't a state change.
+fn receive_client(websocket: &mut WebSocket<TcpStream>) -> Message {
+    loop {
+        if let tungstenite::Message::Text(text) = websocket.read_message().unwrap() {
+            match serde_json::from_str(&text).unwrap() {
+                Message::StateChange(_) => {},
+                message => return message,
+            }
+        }
+    }
+}
+
+fn ballot(round: u64) -> String {
+    let action = Action { from: (4, 6), to: (4, 4) };
+    serde_json::to_string(&Message::Ballot(Ballot { action: action, round: round })).unwrap()
+}
+
+#[test]
+fn second_hello_does_not_change_identity() {
+    let host = free_address();
+    let mut engine = Engine::start("identity", host);
+    let mut alice = connect_client(host, "alice");
+    let mut bob = connect_client(host, "bob");
+
+    engine.send(&Message::VoteCall(VoteCall {
+        round: 1,
+        position: 0,
+        deadline: common::now_millis() + 1000,
+    }));
+    for client in &mut [&mut alice, &mut bob] {
+        match receive_client(client) {
+            Message::VoteCall(vote_call) => assert_eq!(vote_call.round, 1),
+            message => panic!("expected vote call, got {:?}", message),
+        }
+    }
+    send_client(&mut alice, &ballot(1));
+
+    // alice tries to become bob and vote again
+    send_client(&mut alice, &hello("bob"));
+    match receive_client(&mut alice) {
+        Message::Error(_) => {},
+        message => panic!("expected error, got {:?}", message),
+    }
+    send_client(&mut alice, &ballot(1));
+    thread::sleep(Duration::from_millis(100));
+
+    // so bob's ballot is still his own
+    send_client(&mut bob, &ballot(1));
+
+    loop {
+        if let Message::Vote(vote) = engine.receive() {
+            assert_eq!((vote.round, vote.weight), (Some(1), 2));
+            return;
+        }
+    }
+}
+
+#[test]
+fn rejected_client_hears_why_before_it_is_closed() {
+    let host = free_address();
+    let _engine = Engine::start("rejected", host);
+
+    let stream = TcpStream::connect(host).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let url = url::Url::parse(&format!("ws://{}", host)).unwrap();
+    let (mut websocket, _) = tungstenite::client(url, stream).ok().unwrap();
+    let hello = Message::Hello(Hello::new(Vec::new(), false));
+    send_client(&mut websocket, &serde_json::to_string(&hello).unwrap());
+
+    match receive_client(&mut websocket) {
+        Message::Error(error) => assert_eq!(error.error, "token required"),
+        message => panic!("expected error, got {:?}", message),
+    }
+    match websocket.read_message() {
+        Err(tungstenite::Error::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+            panic!("funnel left the rejected client connected")
+        },
+        Err(_) => {},
+        Ok(message) => panic!("expected the connection to close, got {:?}", message),
+    }
+}
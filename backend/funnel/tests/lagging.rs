@@ -0,0 +1,132 @@
+//! A client that stops reading is cut off once too much is waiting for it, rather than
+//! having everything sent to it pile up in the funnel.
+
+extern crate common;
+extern crate funnel;
+extern crate serde_json;
+extern crate tungstenite;
+extern crate url;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{Board, Encoding, Hello, Message, PieceColour, Pong, StateChange, Welcome};
+use tungstenite::WebSocket;
+
+fn free_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// The engine's end of the funnel's link.
+struct Engine {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Engine {
+    /// Starts a funnel on `host` that lets 64 KiB wait for each client, beneath a new engine
+    /// link, and welcomes it.
+    fn start(host: SocketAddr) -> Engine {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = std::env::temp_dir().join("funnel-lagging.toml");
+        let mut file = File::create(&path).unwrap();
+        write!(
+            file,
+            "host = \"{}\"\nupstream = \"{}\"\nupstream_is_websocket = false\n\
+             max_queued_bytes = 65536\n",
+            host,
+            listener.local_addr().unwrap()
+        ).unwrap();
+        let config = funnel::read_config(&path);
+        thread::spawn(move || funnel::run(config));
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut engine = Engine {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream: stream,
+        };
+        match engine.receive() {
+            Message::Hello(_) => {},
+            message => panic!("expected hello, got {:?}", message),
+        }
+        engine.send(&Message::Welcome(Welcome {
+            version: common::PROTOCOL_VERSION,
+            encoding: Encoding::Json,
+        }));
+        engine
+    }
+
+    fn send(&mut self, message: &Message) {
+        let mut line = serde_json::to_string(message).unwrap();
+        line.push('\n');
+        self.stream.write_all(line.as_bytes()).unwrap();
+    }
+
+    /// Reads the next message, answering pings on the way so the funnel keeps the link.
+    fn receive(&mut self) -> Message {
+        loop {
+            let mut line = String::new();
+            assert!(self.reader.read_line(&mut line).unwrap() > 0, "funnel hung up");
+            match serde_json::from_str(&line).unwrap() {
+                Message::Ping(ping) => self.send(&Message::Pong(Pong::reply(&ping))),
+                message => return message,
+            }
+        }
+    }
+}
+
+fn connect_client(address: SocketAddr) -> WebSocket<TcpStream> {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let url = url::Url::parse(&format!("ws://{}", address)).unwrap();
+    let (mut websocket, _) = tungstenite::client(url, stream).ok().unwrap();
+    let hello = serde_json::to_string(&Message::Hello(Hello::new(Vec::new(), false))).unwrap();
+    websocket.write_message(tungstenite::Message::Text(hello)).unwrap();
+    loop {
+        if let tungstenite::Message::Text(text) = websocket.read_message().unwrap() {
+            if let Message::Welcome(_) = serde_json::from_str(&text).unwrap() {
+                return websocket;
+            }
+        }
+    }
+}
+
+#[test]
+fn client_that_stops_reading_is_disconnected() {
+    let host = free_address();
+    let mut engine = Engine::start(host);
+    let mut client = connect_client(host);
+
+    // far more than the socket buffers hold, with the client reading none of it
+    let mut states = String::new();
+    for sequence in 0..20_000 {
+        let state = Message::StateChange(StateChange {
+            board: Board([[None; 8]; 8]),
+            turn: PieceColour::White,
+            sequence: sequence,
+        });
+        states.push_str(&serde_json::to_string(&state).unwrap());
+        states.push('\n');
+    }
+    engine.stream.write_all(states.as_bytes()).unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    // what made it into the socket buffers can still be read, and then the connection ends
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        assert!(Instant::now() < deadline, "client was never disconnected");
+        match client.read_message() {
+            Ok(_) => {},
+            Err(tungstenite::Error::Io(ref e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                panic!("client is still connected with nothing to read")
+            },
+            Err(_) => return,
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! A funnel finding its upstream: retrying until it is up, moving on from one that never
+//! welcomes it, reattaching once it is lost, and stopping when it is turned away. The upstream
+//! here is a plain TCP link speaking JSON lines, as the engine does.
+
+extern crate common;
+extern crate funnel;
+extern crate serde_json;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{Encoding, ErrorMessage, Message, Welcome};
+
+fn free_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Runs a funnel beneath a TCP upstream on `upstream`, which need not be listening yet. What
+/// the funnel returns, if it stops, arrives on the receiver.
+fn start_funnel(name: &str, upstream: SocketAddr) -> mpsc::Receiver<io::Result<()>> {
+    let path = std::env::temp_dir().join(format!("funnel-reattach-{}.toml", name));
+    let mut file = File::create(&path).unwrap();
+    write!(
+        file,
+        "host = \"{}\"\nupstream = \"{}\"\nupstream_is_websocket = false\n",
+        free_address(),
+        upstream
+    ).unwrap();
+    let config = funnel::read_config(&path);
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(funnel::run(config)));
+    receiver
+}
+
+/// Waits for the funnel to connect, failing the test after `within`.
+fn accept(listener: &TcpListener, within: Duration) -> TcpStream {
+    listener.set_nonblocking(true).unwrap();
+    let deadline = Instant::now() + within;
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false).unwrap();
+                stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                return stream;
+            },
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+            Err(e) => panic!("funnel did not connect: {}", e),
+        }
+    }
+}
+
+/// Reads messages until the funnel's `Hello`.
+fn expect_hello(stream: &TcpStream) {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        assert!(reader.read_line(&mut line).unwrap() > 0, "funnel hung up before saying hello");
+        if let Message::Hello(_) = serde_json::from_str(&line).unwrap() {
+            return;
+        }
+    }
+}
+
+fn welcome(mut stream: &TcpStream) {
+    let welcome = Message::Welcome(Welcome {
+        version: common::PROTOCOL_VERSION,
+        encoding: Encoding::Json,
+    });
+    let mut line = serde_json::to_string(&welcome).unwrap();
+    line.push('\n');
+    stream.write_all(line.as_bytes()).unwrap();
+}
+
+#[test]
+fn retries_upstream_until_it_is_up() {
+    let address = free_address();
+    start_funnel("retry", address);
+    thread::sleep(Duration::from_millis(500));
+
+    let listener = TcpListener::bind(address).unwrap();
+    let upstream = accept(&listener, Duration::from_secs(10));
+    expect_hello(&upstream);
+}
+
+#[test]
+fn moves_on_from_an_upstream_that_never_welcomes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    start_funnel("silent", listener.local_addr().unwrap());
+
+    let silent = accept(&listener, Duration::from_secs(5));
+    expect_hello(&silent);
+    let started = Instant::now();
+
+    // left unanswered, the funnel gives up on this link and tries again
+    let next = accept(&listener, Duration::from_secs(10));
+    assert!(started.elapsed() >= Duration::from_secs(4), "gave up after {:?}", started.elapsed());
+    expect_hello(&next);
+    let mut rest = String::new();
+    assert_eq!(BufReader::new(&silent).read_line(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn reattaches_after_losing_upstream() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    start_funnel("lost", listener.local_addr().unwrap());
+
+    let first = accept(&listener, Duration::from_secs(5));
+    expect_hello(&first);
+    welcome(&first);
+    thread::sleep(Duration::from_millis(200));
+    drop(first);
+
+    let second = accept(&listener, Duration::from_secs(3));
+    expect_hello(&second);
+    welcome(&second);
+
+    // welcomed, the new link is kept past the time an unanswered one is given
+    thread::sleep(Duration::from_secs(6));
+    listener.set_nonblocking(true).unwrap();
+    assert!(listener.accept().is_err(), "funnel left a parent that welcomed it");
+}
+
+#[test]
+fn stops_when_upstream_refuses_it() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stopped = start_funnel("refused", listener.local_addr().unwrap());
+
+    let mut stream = accept(&listener, Duration::from_secs(5));
+    expect_hello(&stream);
+    let error = Message::Error(ErrorMessage { error: "not today".to_owned() });
+    let mut line = serde_json::to_string(&error).unwrap();
+    line.push('\n');
+    stream.write_all(line.as_bytes()).unwrap();
+
+    let result = stopped.recv_timeout(Duration::from_secs(5)).expect("funnel kept running");
+    let error = result.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(error.to_string().contains("not today"));
+}
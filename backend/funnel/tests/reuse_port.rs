@@ -0,0 +1,219 @@
+extern crate common;
+extern crate funnel;
+extern crate mio;
+extern crate serde_json;
+extern crate tungstenite;
+extern crate url;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::{Action, Ballot, Encoding, Hello, Message, Node, Pong, VoteCall, Welcome};
+use funnel::listener;
+use tungstenite::WebSocket;
+
+fn free_address() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+fn accept_all(listener: &mio::net::TcpListener) -> usize {
+    let mut accepted = 0;
+    loop {
+        match listener.accept() {
+            Ok(_) => accepted += 1,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return accepted,
+            Err(e) => panic!("accept failed: {}", e),
+        }
+    }
+}
+
+#[test]
+fn port_is_exclusive_by_default() {
+    let address = free_address();
+    let _first = listener::bind(&address, false).unwrap();
+    assert!(listener::bind(&address, false).is_err());
+}
+
+#[test]
+fn siblings_share_connections() {
+    let address = free_address();
+    let first = listener::bind(&address, true).unwrap();
+    let second = listener::bind(&address, true).unwrap();
+
+    let connections: Vec<_> = (0..64).map(|_| TcpStream::connect(&address).unwrap()).collect();
+    // give the kernel a moment to finish the handshakes
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    let first = accept_all(&first);
+    let second = accept_all(&second);
+    assert_eq!(first + second, connections.len());
+    assert!(first > 0 && second > 0, "connections went {} / {}", first, second);
+}
+
+/// Runs a funnel from the given config lines, beneath the upstream at `upstream`.
+fn start_funnel(name: &str, host: SocketAddr, upstream: SocketAddr, websocket: bool, extra: &str) {
+    let path = std::env::temp_dir().join(format!("funnel-reuse-port-{}.toml", name));
+    let mut file = File::create(&path).unwrap();
+    write!(
+        file,
+        "host = \"{}\"\nupstream = \"{}\"\nupstream_is_websocket = {}\n\
+         ping_interval = {{ secs = 0, nanos = 200000000 }}\n{}",
+        host, upstream, websocket, extra
+    ).unwrap();
+    let config = funnel::read_config(&path);
+    thread::spawn(move || funnel::run(config));
+}
+
+/// The engine's end of the root funnel's link, speaking JSON lines.
+struct Engine {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Engine {
+    fn accept(listener: &TcpListener) -> Engine {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        Engine {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream: stream,
+        }
+    }
+
+    fn send(&mut self, message: &Message) {
+        let mut line = serde_json::to_string(message).unwrap();
+        line.push('\n');
+        self.stream.write_all(line.as_bytes()).unwrap();
+    }
+
+    /// Reads the next message, answering pings on the way so the funnel keeps the link.
+    fn receive(&mut self) -> Message {
+        loop {
+            let mut line = String::new();
+            assert!(self.reader.read_line(&mut line).unwrap() > 0, "root funnel hung up");
+            match serde_json::from_str(&line).unwrap() {
+                Message::Ping(ping) => self.send(&Message::Pong(Pong::reply(&ping))),
+                message => return message,
+            }
+        }
+    }
+
+    /// Reads topology reports until `done` accepts one, failing the test after `within`.
+    fn wait_for_topology<F: Fn(&Node) -> bool>(&mut self, within: Duration, done: F) {
+        let deadline = Instant::now() + within;
+        while Instant::now() < deadline {
+            if let Message::Topology(node) = self.receive() {
+                if done(&node) {
+                    return;
+                }
+            }
+        }
+        panic!("topology never settled");
+    }
+}
+
+fn child<'a>(node: &'a Node, id: &str) -> Option<&'a Node> {
+    node.children.iter().find(|child| child.id == id)
+}
+
+fn connect_client(address: SocketAddr) -> WebSocket<TcpStream> {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let url = url::Url::parse(&format!("ws://{}", address)).unwrap();
+    let (mut websocket, _) = tungstenite::client(url, stream).ok().unwrap();
+    send_client(&mut websocket, &Message::Hello(Hello::new(Vec::new(), false)));
+    websocket
+}
+
+fn send_client(websocket: &mut WebSocket<TcpStream>, message: &Message) {
+    let text = serde_json::to_string(message).unwrap();
+    websocket.write_message(tungstenite::Message::Text(text)).unwrap();
+}
+
+/// Reads from a client until it is called to vote.
+fn expect_vote_call(websocket: &mut WebSocket<TcpStream>) -> u64 {
+    loop {
+        if let tungstenite::Message::Text(text) = websocket.read_message().unwrap() {
+            if let Message::VoteCall(vote_call) = serde_json::from_str(&text).unwrap() {
+                return vote_call.round;
+            }
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 1000 + u64::from(now.subsec_nanos()) / 1_000_000
+}
+
+#[test]
+fn siblings_both_vote_through_their_parent() {
+    let engine_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let parent = free_address();
+    start_funnel(
+        "parent",
+        parent,
+        engine_listener.local_addr().unwrap(),
+        false,
+        "[[children]]\nid = \"eu-1\"\nkey = \"one\"\n\
+         [[children]]\nid = \"eu-2\"\nkey = \"two\"\n",
+    );
+    let mut engine = Engine::accept(&engine_listener);
+    match engine.receive() {
+        Message::Hello(_) => {},
+        message => panic!("expected hello, got {:?}", message),
+    }
+    engine.send(&Message::Welcome(Welcome {
+        version: common::PROTOCOL_VERSION,
+        encoding: Encoding::Json,
+    }));
+
+    let shared = free_address();
+    for &(id, key) in &[("eu-1", "one"), ("eu-2", "two")] {
+        let extra = format!(
+            "reuse_port = true\n[upstream_auth]\nid = \"{}\"\nkey = \"{}\"\n",
+            id, key
+        );
+        start_funnel(id, shared, parent, true, &extra);
+    }
+    engine.wait_for_topology(Duration::from_secs(10), |node| {
+        child(node, "eu-1").is_some() && child(node, "eu-2").is_some()
+    });
+
+    let mut clients: Vec<_> = (0..16).map(|_| connect_client(shared)).collect();
+    engine.wait_for_topology(Duration::from_secs(10), |node| {
+        match (child(node, "eu-1"), child(node, "eu-2")) {
+            (Some(first), Some(second)) => {
+                first.clients > 0 && second.clients > 0 && node.subtree_clients() == clients.len()
+            },
+            _ => false,
+        }
+    });
+
+    engine.send(&Message::VoteCall(VoteCall {
+        round: 1,
+        position: 0,
+        deadline: now_millis() + 3000,
+    }));
+    let action = Action { from: (4, 6), to: (4, 4) };
+    for client in &mut clients {
+        let round = expect_vote_call(client);
+        send_client(client, &Message::Ballot(Ballot {
+            action: action.clone(),
+            round: round,
+        }));
+    }
+
+    loop {
+        if let Message::Vote(vote) = engine.receive() {
+            assert_eq!(vote.round, Some(1));
+            assert_eq!(vote.weight as usize, clients.len());
+            assert_eq!((vote.action.from, vote.action.to), (action.from, action.to));
+            return;
+        }
+    }
+}
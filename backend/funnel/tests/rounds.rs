@@ -0,0 +1,164 @@
+//! Which round a ballot counts in: only the one open when it arrives, and only if it says
+//! so. The upstream here is a plain TCP link speaking JSON lines, as the engine does.
+
+extern crate common;
+extern crate funnel;
+extern crate serde_json;
+extern crate tungstenite;
+extern crate url;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use common::{Action, Ballot, Encoding, Hello, Message, Pong, Vote, VoteCall, Welcome};
+use tungstenite::WebSocket;
+
+fn free_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// The engine's end of the funnel's link.
+struct Engine {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Engine {
+    /// Starts a funnel on `host` beneath a new engine link and welcomes it.
+    fn start(host: SocketAddr) -> Engine {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = std::env::temp_dir().join("funnel-rounds.toml");
+        let mut file = File::create(&path).unwrap();
+        write!(
+            file,
+            "host = \"{}\"\nupstream = \"{}\"\nupstream_is_websocket = false\n\
+             timeout_change = {{ secs = 0, nanos = 200000000 }}\n",
+            host,
+            listener.local_addr().unwrap()
+        ).unwrap();
+        let config = funnel::read_config(&path);
+        thread::spawn(move || funnel::run(config));
+
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut engine = Engine {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            stream: stream,
+        };
+        match engine.receive() {
+            Message::Hello(_) => {},
+            message => panic!("expected hello, got {:?}", message),
+        }
+        engine.send(&Message::Welcome(Welcome {
+            version: common::PROTOCOL_VERSION,
+            encoding: Encoding::Json,
+        }));
+        engine
+    }
+
+    fn send(&mut self, message: &Message) {
+        let mut line = serde_json::to_string(message).unwrap();
+        line.push('\n');
+        self.stream.write_all(line.as_bytes()).unwrap();
+    }
+
+    /// Reads the next message, answering pings on the way so the funnel keeps the link.
+    fn receive(&mut self) -> Message {
+        loop {
+            let mut line = String::new();
+            assert!(self.reader.read_line(&mut line).unwrap() > 0, "funnel hung up");
+            match serde_json::from_str(&line).unwrap() {
+                Message::Ping(ping) => self.send(&Message::Pong(Pong::reply(&ping))),
+                message => return message,
+            }
+        }
+    }
+
+    /// Calls a vote and waits for the funnel's answer.
+    fn call_vote(&mut self, round: u64, client: &mut WebSocket<TcpStream>) -> Vote {
+        self.send(&Message::VoteCall(VoteCall {
+            round: round,
+            position: 0,
+            deadline: common::now_millis() + 500,
+        }));
+        assert_eq!(expect_vote_call(client), round);
+        loop {
+            if let Message::Vote(vote) = self.receive() {
+                return vote;
+            }
+        }
+    }
+}
+
+fn connect_client(address: SocketAddr) -> WebSocket<TcpStream> {
+    let stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let url = url::Url::parse(&format!("ws://{}", address)).unwrap();
+    let (mut websocket, _) = tungstenite::client(url, stream).ok().unwrap();
+    let hello = serde_json::to_string(&Message::Hello(Hello::new(Vec::new(), false))).unwrap();
+    send_client(&mut websocket, &hello);
+    // a client only hears vote calls once it is welcomed
+    loop {
+        if let tungstenite::Message::Text(text) = websocket.read_message().unwrap() {
+            if let Message::Welcome(_) = serde_json::from_str(&text).unwrap() {
+                return websocket;
+            }
+        }
+    }
+}
+
+fn send_client(websocket: &mut WebSocket<TcpStream>, text: &str) {
+    websocket.write_message(tungstenite::Message::Text(text.to_owned())).unwrap();
+}
+
+fn ballot(round: u64) -> String {
+    let action = Action { from: (4, 6), to: (4, 4) };
+    serde_json::to_string(&Message::Ballot(Ballot { action: action, round: round })).unwrap()
+}
+
+/// Reads from a client until it is called to vote.
+fn expect_vote_call(websocket: &mut WebSocket<TcpStream>) -> u64 {
+    loop {
+        if let tungstenite::Message::Text(text) = websocket.read_message().unwrap() {
+            if let Message::VoteCall(vote_call) = serde_json::from_str(&text).unwrap() {
+                return vote_call.round;
+            }
+        }
+    }
+}
+
+#[test]
+fn late_ballots_do_not_count_in_the_next_round() {
+    let host = free_address();
+    let mut engine = Engine::start(host);
+    let mut client = connect_client(host);
+
+    let vote = engine.call_vote(1, &mut client);
+    assert_eq!((vote.round, vote.weight), (Some(1), 0));
+
+    // too late for round 1, and no round at all
+    send_client(&mut client, &ballot(1));
+    send_client(&mut client, r#"{"type": "ballot", "action": {"from": [4, 6], "to": [4, 4]}}"#);
+    thread::sleep(Duration::from_millis(200));
+
+    let vote = engine.call_vote(2, &mut client);
+    assert_eq!((vote.round, vote.weight), (Some(2), 0));
+
+    engine.send(&Message::VoteCall(VoteCall {
+        round: 3,
+        position: 0,
+        deadline: common::now_millis() + 500,
+    }));
+    assert_eq!(expect_vote_call(&mut client), 3);
+    send_client(&mut client, &ballot(3));
+    loop {
+        if let Message::Vote(vote) = engine.receive() {
+            assert_eq!((vote.round, vote.weight), (Some(3), 1));
+            return;
+        }
+    }
+}
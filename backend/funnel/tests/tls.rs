@@ -0,0 +1,149 @@
+//! Round trips over the TLS streams with the self-signed certificates in `tests/data`.
+//! `ca.pem` signed `localhost.pem`, which is valid for the name `localhost`.
+
+extern crate funnel;
+extern crate mio;
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use mio::net::TcpStream;
+
+use funnel::listener;
+use funnel::tls::{self, TlsStream};
+
+fn data(name: &str) -> String {
+    format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Connects a pair of non-blocking sockets over localhost.
+fn socket_pair() -> (TcpStream, TcpStream) {
+    let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listener = listener::bind(&address, false).unwrap();
+    let client = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match listener.accept() {
+            Ok((server, _)) => return (client, server),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock && Instant::now() < deadline => {},
+            Err(e) => panic!("accept failed: {}", e),
+        }
+    }
+}
+
+/// Reads `length` bytes from `reader`. `other` is read from too, so that both ends of the
+/// handshake make progress, but nothing is expected from it.
+fn read_exact<A: Read, B: Read>(reader: &mut A, other: &mut B, length: usize) -> io::Result<Vec<u8>> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut received = Vec::new();
+    let mut buffer = [0; 64];
+    while received.len() < length {
+        if Instant::now() > deadline {
+            return Err(io::Error::new(ErrorKind::TimedOut, "timed out"));
+        }
+
+        match other.read(&mut buffer) {
+            Ok(_) => {},
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+            Err(e) => return Err(e),
+        }
+        match reader.read(&mut buffer) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "closed")),
+            Ok(read) => received.extend_from_slice(&buffer[..read]),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(received)
+}
+
+#[test]
+fn round_trip_with_self_signed_certificate() {
+    let server_config = tls::server_config(
+        Path::new(&data("localhost.pem")),
+        Path::new(&data("localhost.key")),
+    ).unwrap();
+    let client_config = tls::client_config(Some(Path::new(&data("ca.pem")))).unwrap();
+
+    let (client, server) = socket_pair();
+    let mut client = TlsStream::connect(client, &client_config, "localhost").unwrap();
+    let mut server = TlsStream::accept(server, &server_config);
+
+    client.write_all(b"hello").unwrap();
+    assert_eq!(read_exact(&mut server, &mut client, 5).unwrap(), b"hello");
+
+    server.write_all(b"world").unwrap();
+    assert_eq!(read_exact(&mut client, &mut server, 5).unwrap(), b"world");
+}
+
+#[test]
+fn untrusted_certificate_is_rejected() {
+    let server_config = tls::server_config(
+        Path::new(&data("localhost.pem")),
+        Path::new(&data("localhost.key")),
+    ).unwrap();
+    // only the usual web roots, which did not sign the test certificate
+    let client_config = tls::client_config(None).unwrap();
+
+    let (client, server) = socket_pair();
+    let mut client = TlsStream::connect(client, &client_config, "localhost").unwrap();
+    let mut server = TlsStream::accept(server, &server_config);
+
+    client.write_all(b"hello").unwrap();
+    let error = read_exact(&mut client, &mut server, 1).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn slow_reader_pushes_back() {
+    let server_config = tls::server_config(
+        Path::new(&data("localhost.pem")),
+        Path::new(&data("localhost.key")),
+    ).unwrap();
+    let client_config = tls::client_config(Some(Path::new(&data("ca.pem")))).unwrap();
+
+    let (client, server) = socket_pair();
+    let mut client = TlsStream::connect(client, &client_config, "localhost").unwrap();
+    let mut server = TlsStream::accept(server, &server_config);
+    client.write_all(b"hello").unwrap();
+    assert_eq!(read_exact(&mut server, &mut client, 5).unwrap(), b"hello");
+
+    // with the client reading nothing, the server fills the socket and then its session
+    let chunk = [0; 16 * 1024];
+    let mut written = 0;
+    let blocked = loop {
+        match server.write(&chunk) {
+            Ok(length) => written += length,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break true,
+            Err(e) => panic!("write failed: {}", e),
+        }
+        if written > 256 * 1024 * 1024 {
+            break false;
+        }
+    };
+    assert!(blocked, "took {} bytes without pushing back", written);
+
+    // once the client catches up the server can write again, sending on what its session
+    // held back as the socket drains
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let mut buffer = [0; 64 * 1024];
+    let mut received = 0;
+    while received < written {
+        assert!(Instant::now() < deadline, "only {} of {} bytes arrived", received, written);
+        match server.flush() {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+            result => result.unwrap(),
+        }
+        match client.read(&mut buffer) {
+            Ok(0) => panic!("server closed"),
+            Ok(read) => received += read,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+            Err(e) => panic!("read failed: {}", e),
+        }
+    }
+    server.flush().unwrap();
+    assert!(server.write(&chunk).is_ok());
+}
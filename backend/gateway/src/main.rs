@@ -0,0 +1,398 @@
+//! Points new clients at the least loaded leaf funnel.
+//!
+//! Leaf funnels send their `Load` to `reports` once every ping interval, signed with a key
+//! registered here under their id. `GET /connect` on `host` answers with the URL of the funnel
+//! with the fewest clients, out of those heard from within `stale_after` that are attached to
+//! a parent.
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate serde_json;
+extern crate toml;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate common;
+
+use common::{Load, LoadReport, token};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize)]
+struct Config {
+    /// Where to answer HTTP requests.
+    host: SocketAddr,
+    /// Where funnels send their load reports.
+    reports: SocketAddr,
+    /// How long a funnel counts as healthy after its last report.
+    #[serde(default = "default_stale_after")]
+    stale_after: Duration,
+    /// The funnels allowed to report their load. Reports from anyone else are ignored.
+    funnels: Vec<FunnelConfig>,
+}
+
+/// A funnel's registration with the gateway, shared by both.
+#[derive(Deserialize)]
+struct FunnelConfig {
+    id: String,
+    key: String,
+}
+
+fn default_stale_after() -> Duration {
+    Duration::from_secs(15)
+}
+
+fn read_config<P: AsRef<Path>>(path: P) -> Config {
+    let mut file = File::open(&path)
+        .unwrap_or_else(|e| panic!("Could not open config file {:?}: {}", path.as_ref(), e));
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).expect("Reading config file failed");
+    toml::from_str(&contents).expect("Format file incorrectly formatted")
+}
+
+/// Longest request head we read before giving up on a client.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Leaf {
+    load: Load,
+    heard: Instant,
+    /// Clients sent its way since it last reported.
+    assigned: usize,
+}
+
+struct Leaves {
+    /// The key each funnel allowed to report signs with, by id.
+    keys: HashMap<String, String>,
+    /// When each funnel sent the last report taken from it, kept after the funnel goes stale
+    /// so an old report can't bring it back.
+    last_sent: HashMap<String, u64>,
+    leaves: HashMap<String, Leaf>,
+    stale_after: Duration,
+}
+
+impl Leaves {
+    fn new(funnels: Vec<FunnelConfig>, stale_after: Duration) -> Leaves {
+        Leaves {
+            keys: funnels.into_iter().map(|funnel| (funnel.id, funnel.key)).collect(),
+            last_sent: HashMap::new(),
+            leaves: HashMap::new(),
+            stale_after: stale_after,
+        }
+    }
+
+    /// Takes a report if it is signed by a registered funnel and newer than its last one.
+    fn report(&mut self, report: &LoadReport, now: Instant) -> Result<(), String> {
+        let load: Load = serde_json::from_str(&report.load)
+            .map_err(|e| format!("badly formatted load: {}", e))?;
+        let signed = match self.keys.get(&load.id) {
+            Some(key) => token::check_mac(key.as_bytes(), &report.load, &report.signature),
+            None => return Err(format!("{} is not a registered funnel", load.id)),
+        };
+        if !signed {
+            return Err(format!("bad signature for {}", load.id));
+        }
+        if self.last_sent.get(&load.id).map_or(false, |&last| load.sent <= last) {
+            return Err(format!("{} sent a newer report already", load.id));
+        }
+
+        self.last_sent.insert(load.id.clone(), load.sent);
+        let leaf = Leaf {
+            load: load,
+            heard: now,
+            assigned: 0,
+        };
+        self.leaves.insert(leaf.load.id.clone(), leaf);
+        self.forget_stale(now);
+        Ok(())
+    }
+
+    fn forget_stale(&mut self, now: Instant) {
+        let stale_after = self.stale_after;
+        self.leaves.retain(|_, leaf| now.duration_since(leaf.heard) < stale_after);
+    }
+
+    /// Picks the least loaded healthy funnel. It counts as one client busier until its next
+    /// report, so a burst of clients is spread out rather than all sent to the same funnel.
+    fn assign(&mut self, now: Instant) -> Option<String> {
+        self.forget_stale(now);
+        let leaf = self.leaves
+            .values_mut()
+            .filter(|leaf| leaf.load.attached)
+            .min_by_key(|leaf| leaf.load.clients + leaf.assigned);
+        match leaf {
+            Some(leaf) => {
+                leaf.assigned += 1;
+                Some(leaf.load.url.clone())
+            },
+            None => None,
+        }
+    }
+}
+
+fn receive_reports(socket: UdpSocket, leaves: Arc<Mutex<Leaves>>) {
+    let mut buffer = [0; 65536];
+    loop {
+        let (size, sender) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Receiving load report failed: {}", e);
+                continue;
+            },
+        };
+        let report = match serde_json::from_slice::<LoadReport>(&buffer[..size]) {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("Badly formatted load report from {}: {}", sender, e);
+                continue;
+            },
+        };
+        match leaves.lock().unwrap().report(&report, Instant::now()) {
+            Ok(()) => debug!("Load from {}: {}", sender, report.load),
+            Err(e) => warn!("Ignoring load report from {}: {}", sender, e),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Assignment {
+    url: String,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    error: String,
+}
+
+/// Reads the request line and skips the headers, returning the method and path.
+fn read_request(stream: &TcpStream) -> io::Result<(String, String)> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_HEAD as u64));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Request head cut short"));
+        }
+        if header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => {
+            let path = target.split('?').next().unwrap_or(target);
+            Ok((method.to_owned(), path.to_owned()))
+        },
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed request line")),
+    }
+}
+
+fn respond(mut stream: &TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+fn failure(error: &str) -> String {
+    serde_json::to_string(&Failure { error: error.to_owned() }).unwrap()
+}
+
+fn serve(stream: TcpStream, leaves: &Mutex<Leaves>) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let (method, path) = match read_request(&stream) {
+        Ok(request) => request,
+        Err(e) => return respond(&stream, "400 Bad Request", &failure(&format!("{}", e))),
+    };
+
+    match (&method[..], &path[..]) {
+        ("GET", "/connect") => {
+            let assigned = leaves.lock().unwrap().assign(Instant::now());
+            match assigned {
+                Some(url) => {
+                    info!("Sending client {} to {}", peer, url);
+                    let body = serde_json::to_string(&Assignment { url: url }).unwrap();
+                    respond(&stream, "200 OK", &body)
+                },
+                None => {
+                    warn!("No healthy funnel for client {}", peer);
+                    respond(&stream, "503 Service Unavailable", &failure("no funnel available"))
+                },
+            }
+        },
+        (_, "/connect") => respond(&stream, "405 Method Not Allowed", &failure("use GET")),
+        _ => respond(&stream, "404 Not Found", &failure("not found")),
+    }
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("USAGE: gateway configpath");
+        std::process::exit(1);
+    }
+    let config = read_config(&args[1]);
+
+    let leaves = Arc::new(Mutex::new(Leaves::new(config.funnels, config.stale_after)));
+
+    let reports = UdpSocket::bind(config.reports).expect("Could not bind to reports address");
+    info!("Receiving load reports on {}", config.reports);
+    {
+        let leaves = leaves.clone();
+        thread::Builder::new()
+            .name("reports".to_owned())
+            .spawn(move || receive_reports(reports, leaves))
+            .expect("Could not start reports thread");
+    }
+
+    let listener = TcpListener::bind(config.host).expect("Could not bind to host");
+    info!("Listening on {}", config.host);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Accepting connection failed: {}", e);
+                continue;
+            },
+        };
+        let leaves = leaves.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, &leaves) {
+                warn!("Request failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves() -> Leaves {
+        let funnels = ["a", "b", "c"]
+            .iter()
+            .map(|id| FunnelConfig {
+                id: id.to_string(),
+                key: format!("{} key", id),
+            })
+            .collect();
+        Leaves::new(funnels, Duration::from_secs(15))
+    }
+
+    fn load(id: &str, clients: usize, attached: bool, sent: u64) -> Load {
+        Load {
+            id: id.to_owned(),
+            url: format!("wss://{}", id),
+            clients: clients,
+            attached: attached,
+            sent: sent,
+        }
+    }
+
+    fn signed(load: &Load) -> LoadReport {
+        LoadReport::new(load, format!("{} key", load.id).as_bytes())
+    }
+
+    #[test]
+    fn assigns_least_loaded() {
+        let mut leaves = leaves();
+        let now = Instant::now();
+        leaves.report(&signed(&load("a", 5, true, 1)), now).unwrap();
+        leaves.report(&signed(&load("b", 3, true, 1)), now).unwrap();
+        assert_eq!(leaves.assign(now), Some("wss://b".to_owned()));
+        assert_eq!(leaves.assign(now), Some("wss://b".to_owned()));
+        // b now counts as 5 clients too, so the next two go one to each
+        let mut next = vec![leaves.assign(now).unwrap(), leaves.assign(now).unwrap()];
+        next.sort();
+        assert_eq!(next, vec!["wss://a".to_owned(), "wss://b".to_owned()]);
+    }
+
+    #[test]
+    fn skips_detached() {
+        let mut leaves = leaves();
+        let now = Instant::now();
+        leaves.report(&signed(&load("a", 5, true, 1)), now).unwrap();
+        leaves.report(&signed(&load("b", 0, false, 1)), now).unwrap();
+        assert_eq!(leaves.assign(now), Some("wss://a".to_owned()));
+
+        leaves.report(&signed(&load("a", 5, false, 2)), now).unwrap();
+        assert_eq!(leaves.assign(now), None);
+    }
+
+    #[test]
+    fn forgets_stale() {
+        let mut leaves = leaves();
+        let start = Instant::now();
+        leaves.report(&signed(&load("a", 0, true, 1)), start).unwrap();
+        let later = start + Duration::from_secs(10);
+        leaves.report(&signed(&load("b", 5, true, 1)), later).unwrap();
+        assert_eq!(leaves.assign(later), Some("wss://a".to_owned()));
+
+        let stale = start + Duration::from_secs(15);
+        assert_eq!(leaves.assign(stale), Some("wss://b".to_owned()));
+        assert!(!leaves.leaves.contains_key("a"));
+        assert_eq!(leaves.assign(later + Duration::from_secs(15)), None);
+        assert!(leaves.leaves.is_empty());
+    }
+
+    #[test]
+    fn refuses_forged_reports() {
+        let mut leaves = leaves();
+        let now = Instant::now();
+
+        let unknown = LoadReport::new(&load("x", 0, true, 1), b"x key");
+        assert!(leaves.report(&unknown, now).is_err());
+
+        let mut forged = signed(&load("a", 0, true, 1));
+        forged.load = serde_json::to_string(&load("a", 0, true, 2)).unwrap();
+        assert!(leaves.report(&forged, now).is_err());
+        let wrong_key = LoadReport::new(&load("a", 0, true, 1), b"b key");
+        assert!(leaves.report(&wrong_key, now).is_err());
+
+        assert!(leaves.leaves.is_empty());
+        assert_eq!(leaves.assign(now), None);
+    }
+
+    #[test]
+    fn refuses_replayed_reports() {
+        let mut leaves = leaves();
+        let now = Instant::now();
+        let first = signed(&load("a", 0, true, 1));
+        leaves.report(&first, now).unwrap();
+        leaves.report(&signed(&load("a", 0, false, 2)), now).unwrap();
+        assert!(leaves.report(&first, now).is_err());
+        assert_eq!(leaves.assign(now), None);
+
+        // nor once the funnel has gone stale
+        let stale = now + Duration::from_secs(15);
+        assert!(leaves.report(&first, stale).is_err());
+        assert_eq!(leaves.assign(stale), None);
+    }
+}
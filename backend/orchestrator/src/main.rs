@@ -0,0 +1,465 @@
+//! Brings up a whole tree from one topology file: the engine, then each level of funnels
+//! beneath it.
+//!
+//! Every node gets a free port and a generated config in `work_dir`, next to its log. Nodes
+//! are started parents first, each once the one above it is listening, and restarted if they
+//! crash. Interrupting the orchestrator stops the tree leaves first.
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate toml;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate libc;
+extern crate rand;
+
+use rand::Rng;
+use toml::Value;
+use toml::value::Table;
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize)]
+struct Topology {
+    /// Where generated configs and node logs go.
+    #[serde(default = "default_work_dir")]
+    work_dir: PathBuf,
+    /// Where the engine and funnel binaries are. Defaults to the directory this binary is in.
+    #[serde(default)]
+    bin_dir: Option<PathBuf>,
+    /// The address every node listens on, each on its own free port.
+    #[serde(default = "default_ip")]
+    ip: IpAddr,
+    /// Settings copied into the engine's config.
+    #[serde(default)]
+    engine: Table,
+    /// Levels of funnels, from the one beneath the engine down to the leaves.
+    levels: Vec<Level>,
+}
+
+#[derive(Deserialize)]
+struct Level {
+    /// How many funnels sit beneath each node of the level above. The engine only takes one
+    /// funnel, so this has to be 1 for the first level.
+    #[serde(default = "default_fan_out")]
+    fan_out: usize,
+    /// Settings copied into the config of every funnel on this level.
+    #[serde(default)]
+    config: Table,
+}
+
+fn default_work_dir() -> PathBuf {
+    PathBuf::from("tree")
+}
+
+fn default_ip() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+}
+
+fn default_fan_out() -> usize {
+    1
+}
+
+fn read_topology<P: AsRef<Path>>(path: P) -> Topology {
+    let mut file = File::open(&path)
+        .unwrap_or_else(|e| panic!("Could not open topology file {:?}: {}", path.as_ref(), e));
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).expect("Reading topology file failed");
+    toml::from_str(&contents).expect("Topology file incorrectly formatted")
+}
+
+/// How long a node has to start listening before the nodes beneath it are started anyway.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A node that stays up this long is considered healthy again, and restarts straight away
+/// the next time it dies.
+const STABLE_AFTER: Duration = Duration::from_secs(10);
+
+/// How long a node has to exit once asked before it is killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(500);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn handle_signals() {
+    extern "C" fn shut_down(_: libc::c_int) {
+        SHUTDOWN.store(true, Ordering::SeqCst);
+    }
+    let handler = shut_down as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_signals() {}
+
+/// Asks a node to exit, giving it the chance to close its connections.
+#[cfg(unix)]
+fn terminate(process: &mut Child) {
+    unsafe {
+        libc::kill(process.id() as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate(process: &mut Child) {
+    let _ = process.kill();
+}
+
+/// Finds a port nothing is listening on by having the OS pick one.
+fn free_port(ip: IpAddr) -> io::Result<SocketAddr> {
+    TcpListener::bind((ip, 0))?.local_addr()
+}
+
+fn seconds(secs: u64) -> Value {
+    Value::try_from(Duration::from_secs(secs)).unwrap()
+}
+
+fn registration(id: &str, key: &str) -> Value {
+    let mut registration = Table::new();
+    registration.insert("id".to_owned(), Value::String(id.to_owned()));
+    registration.insert("key".to_owned(), Value::String(key.to_owned()));
+    Value::Table(registration)
+}
+
+struct Funnel {
+    name: String,
+    address: SocketAddr,
+    parent: Option<usize>,
+    key: String,
+    config: Table,
+}
+
+struct Node {
+    name: String,
+    binary: PathBuf,
+    config: PathBuf,
+    log: PathBuf,
+    address: SocketAddr,
+    process: Option<Child>,
+    started: Instant,
+    /// How many times in a row the node has died before it was stable.
+    failures: u32,
+    restart_at: Option<Instant>,
+}
+
+impl Node {
+    fn new(name: String, binary: PathBuf, work_dir: &Path, address: SocketAddr) -> Node {
+        Node {
+            config: work_dir.join(format!("{}.toml", name)),
+            log: work_dir.join(format!("{}.log", name)),
+            name: name,
+            binary: binary,
+            address: address,
+            process: None,
+            started: Instant::now(),
+            failures: 0,
+            restart_at: None,
+        }
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        let log = OpenOptions::new().create(true).append(true).open(&self.log)?;
+        let process = Command::new(&self.binary)
+            .arg(&self.config)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()?;
+        info!("Started {} (pid {}) on {}", self.name, process.id(), self.address);
+        self.process = Some(process);
+        self.started = Instant::now();
+        Ok(())
+    }
+
+    /// Waits for the node to accept connections, so the nodes beneath it have something to
+    /// connect to. Gives up early if it dies.
+    fn wait_until_listening(&mut self) -> bool {
+        let deadline = Instant::now() + START_TIMEOUT;
+        while Instant::now() < deadline {
+            if TcpStream::connect_timeout(&self.address, Duration::from_millis(200)).is_ok() {
+                return true;
+            }
+            match self.process {
+                Some(ref mut process) => match process.try_wait() {
+                    Ok(None) => {},
+                    _ => return false,
+                },
+                None => return false,
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    /// Notices the node dying and schedules its restart, backing off while it keeps dying
+    /// soon after starting.
+    fn supervise(&mut self, now: Instant) {
+        let exited = match self.process {
+            Some(ref mut process) => match process.try_wait() {
+                Ok(status) => status,
+                Err(e) => {
+                    warn!("Checking on {} failed: {}", self.name, e);
+                    None
+                },
+            },
+            None => None,
+        };
+
+        if let Some(status) = exited {
+            self.process = None;
+            if now.duration_since(self.started) < STABLE_AFTER {
+                self.failures += 1;
+            } else {
+                self.failures = 0;
+            }
+            let delay = backoff(self.failures);
+            warn!(
+                "{} exited with {}, restarting in {}s (see {})",
+                self.name,
+                status,
+                delay.as_secs(),
+                self.log.display()
+            );
+            self.restart_at = Some(now + delay);
+        }
+
+        if self.restart_at.map_or(false, |at| now >= at) {
+            self.restart_at = None;
+            if let Err(e) = self.start() {
+                self.failures += 1;
+                let delay = backoff(self.failures);
+                warn!(
+                    "Restarting {} failed: {}, trying again in {}s",
+                    self.name,
+                    e,
+                    delay.as_secs()
+                );
+                self.restart_at = Some(now + delay);
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        let mut process = match self.process.take() {
+            Some(process) => process,
+            None => return,
+        };
+        terminate(&mut process);
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        loop {
+            match process.try_wait() {
+                Ok(Some(_)) => break,
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(100)),
+                _ => {
+                    warn!("{} did not stop in time, killing it", self.name);
+                    let _ = process.kill();
+                    let _ = process.wait();
+                    break;
+                },
+            }
+        }
+        info!("Stopped {}", self.name);
+    }
+}
+
+/// One second after the first failure, doubling up to half a minute.
+fn backoff(failures: u32) -> Duration {
+    Duration::from_secs(1 << failures.saturating_sub(1).min(5))
+}
+
+/// The engine followed by every funnel, each after its parent.
+struct Tree {
+    nodes: Vec<Node>,
+    leaves: Vec<SocketAddr>,
+}
+
+impl Tree {
+    /// Picks ports for every node and writes their configs.
+    fn plan(topology: &Topology, bin_dir: &Path) -> io::Result<Tree> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+        if topology.levels.is_empty() {
+            return Err(invalid("the topology needs at least one level of funnels"));
+        }
+        if topology.levels[0].fan_out != 1 {
+            return Err(invalid("the engine only takes one funnel, so the first fan_out must be 1"));
+        }
+        if topology.levels.iter().any(|level| level.fan_out == 0) {
+            return Err(invalid("every level needs a fan_out of at least 1"));
+        }
+        fs::create_dir_all(&topology.work_dir)?;
+        let work_dir = &topology.work_dir;
+
+        let engine_address = free_port(topology.ip)?;
+        let mut engine = topology.engine.clone();
+        engine.insert("host".to_owned(), Value::String(engine_address.to_string()));
+        engine.entry("vote_length".to_owned()).or_insert_with(|| seconds(10));
+        engine.entry("vote_timeout".to_owned()).or_insert_with(|| seconds(5));
+        let topology_file = work_dir.join("topology.json").to_string_lossy().into_owned();
+        engine.entry("topology_file".to_owned()).or_insert(Value::String(topology_file));
+        let engine_websocket = engine.get("websocket").and_then(Value::as_bool).unwrap_or(false);
+
+        let mut funnels: Vec<Funnel> = Vec::new();
+        let mut above: Vec<Option<usize>> = vec![None];
+        for (depth, level) in topology.levels.iter().enumerate() {
+            let mut this_level = Vec::new();
+            for &parent in &above {
+                for _ in 0..level.fan_out {
+                    let name = format!("funnel-{}-{}", depth + 1, this_level.len());
+                    let address = free_port(topology.ip)?;
+                    let key: String = rand::thread_rng().gen_ascii_chars().take(32).collect();
+
+                    let mut config = level.config.clone();
+                    config.insert("id".to_owned(), Value::String(name.clone()));
+                    config.insert("host".to_owned(), Value::String(address.to_string()));
+                    config.insert("advertise".to_owned(), Value::String(address.to_string()));
+                    let (upstream, websocket) = match parent {
+                        None => (engine_address, engine_websocket),
+                        Some(parent) => {
+                            let auth = registration(&name, &key);
+                            config.insert("upstream_auth".to_owned(), auth);
+                            (funnels[parent].address, true)
+                        },
+                    };
+                    config.insert("upstream".to_owned(), Value::String(upstream.to_string()));
+                    config.insert("upstream_is_websocket".to_owned(), Value::Boolean(websocket));
+
+                    this_level.push(Some(funnels.len()));
+                    funnels.push(Funnel {
+                        name: name,
+                        address: address,
+                        parent: parent,
+                        key: key,
+                        config: config,
+                    });
+                }
+            }
+            above = this_level;
+        }
+
+        // Every ancestor knows every funnel beneath it, so a funnel that loses its parent can
+        // reattach further up.
+        let mut children = vec![Vec::new(); funnels.len()];
+        for funnel in &funnels {
+            let mut ancestor = funnel.parent;
+            while let Some(index) = ancestor {
+                children[index].push(registration(&funnel.name, &funnel.key));
+                ancestor = funnels[index].parent;
+            }
+        }
+
+        let engine_binary = bin_dir.join(format!("engine{}", env::consts::EXE_SUFFIX));
+        let funnel_binary = bin_dir.join(format!("funnel{}", env::consts::EXE_SUFFIX));
+
+        let engine_node = Node::new("engine".to_owned(), engine_binary, work_dir, engine_address);
+        write_config(&engine_node.config, engine)?;
+        let mut nodes = vec![engine_node];
+        for (funnel, children) in funnels.into_iter().zip(children) {
+            let mut config = funnel.config;
+            config.insert("children".to_owned(), Value::Array(children));
+            let node = Node::new(funnel.name, funnel_binary.clone(), work_dir, funnel.address);
+            write_config(&node.config, config)?;
+            nodes.push(node);
+        }
+
+        let leaves = above
+            .iter()
+            .filter_map(|&index| index)
+            .map(|index| nodes[index + 1].address)
+            .collect();
+        Ok(Tree {
+            nodes: nodes,
+            leaves: leaves,
+        })
+    }
+
+    /// Starts each node once its parent is listening.
+    fn start(&mut self) {
+        for node in &mut self.nodes {
+            if SHUTDOWN.load(Ordering::SeqCst) {
+                return;
+            }
+            if let Err(e) = node.start() {
+                warn!("Starting {} failed: {}", node.name, e);
+                node.failures = 1;
+                node.restart_at = Some(Instant::now() + backoff(node.failures));
+                continue;
+            }
+            if !node.wait_until_listening() {
+                warn!("{} is not listening on {}, carrying on", node.name, node.address);
+            }
+        }
+        for leaf in &self.leaves {
+            info!("Clients can connect to {}", leaf);
+        }
+    }
+
+    fn supervise(&mut self) {
+        let now = Instant::now();
+        for node in &mut self.nodes {
+            node.supervise(now);
+        }
+    }
+
+    /// Stops the leaves first, so no funnel is left reattaching to one about to go.
+    fn stop(&mut self) {
+        for node in self.nodes.iter_mut().rev() {
+            node.stop();
+        }
+    }
+}
+
+fn write_config(path: &Path, config: Table) -> io::Result<()> {
+    let contents = toml::to_string(&Value::Table(config))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut file = File::create(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+fn main() {
+    env_logger::init().unwrap();
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprintln!("USAGE: orchestrator topologypath");
+        std::process::exit(1);
+    }
+    let topology = read_topology(&args[1]);
+
+    let bin_dir = match topology.bin_dir {
+        Some(ref bin_dir) => bin_dir.clone(),
+        None => {
+            let exe = env::current_exe().expect("Could not find the orchestrator binary");
+            exe.parent().expect("Orchestrator binary has no directory").to_owned()
+        },
+    };
+    let mut tree = Tree::plan(&topology, &bin_dir).expect("Could not plan the tree");
+    info!("Wrote node configs to {}", topology.work_dir.display());
+
+    handle_signals();
+    tree.start();
+    while !SHUTDOWN.load(Ordering::SeqCst) {
+        tree.supervise();
+        thread::sleep(SUPERVISE_INTERVAL);
+    }
+
+    info!("Shutting down");
+    tree.stop();
+}
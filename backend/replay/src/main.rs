@@ -0,0 +1,153 @@
+//! Rebuilds the game from an engine's `event_log`, checking every logged outcome against what
+//! the rules give for the votes before it.
+//!
+//! `replay LOG` checks the whole log and prints the game as it ends up. `--to LINE` stops
+//! after that line of the log instead, and `--step` prints the game after every event,
+//! waiting for Enter before going on.
+
+extern crate serde_json;
+
+extern crate common;
+extern crate engine;
+
+use common::{Piece, PieceColour, PieceKind};
+use engine::events::{Record, Replay};
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::process;
+
+struct Options {
+    path: String,
+    to: Option<usize>,
+    step: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("USAGE: replay logpath [--to LINE] [--step]");
+    process::exit(1);
+}
+
+fn parse_args() -> Options {
+    let mut args = env::args().skip(1);
+    let mut options = Options {
+        path: args.next().unwrap_or_else(|| usage()),
+        to: None,
+        step: false,
+    };
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--to" => {
+                let line = args.next().and_then(|line| line.parse().ok());
+                options.to = Some(line.unwrap_or_else(|| usage()));
+            },
+            "--step" => options.step = true,
+            _ => usage(),
+        }
+    }
+    options
+}
+
+fn square(piece: Option<Piece>) -> char {
+    let Piece { kind, colour } = match piece {
+        Some(piece) => piece,
+        None => return '.',
+    };
+    let letter = match kind {
+        PieceKind::King => 'k',
+        PieceKind::Queen => 'q',
+        PieceKind::Rook => 'r',
+        PieceKind::Bishop => 'b',
+        PieceKind::Knight => 'n',
+        PieceKind::Pawn => 'p',
+    };
+    match colour {
+        PieceColour::White => letter.to_ascii_uppercase(),
+        PieceColour::Black => letter,
+    }
+}
+
+fn print_game(replay: &Replay) {
+    let state = replay.state();
+    for row in state.board.0.iter() {
+        let squares: String = row.iter().map(|&piece| square(piece)).collect();
+        println!("    {}", squares);
+    }
+    println!(
+        "game {}, round {}, {} moves played, {:?} to move, position {:016x}",
+        replay.game_number(),
+        replay.round(),
+        replay.history().len(),
+        state.turn,
+        state.position_hash()
+    );
+}
+
+fn main() {
+    let options = parse_args();
+    let file = File::open(&options.path).unwrap_or_else(|e| {
+        eprintln!("Could not open {}: {}", options.path, e);
+        process::exit(1);
+    });
+
+    let stdin = io::stdin();
+    let mut replay = Replay::new();
+    let mut problems = 0;
+    let mut complete = true;
+
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let number = index + 1;
+        if options.to.map_or(false, |to| number > to) {
+            complete = false;
+            break;
+        }
+        let line = line.unwrap_or_else(|e| {
+            eprintln!("Reading {} failed: {}", options.path, e);
+            process::exit(1);
+        });
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: Record = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                problems += 1;
+                println!("line {}: badly formatted: {}", number, e);
+                continue;
+            },
+        };
+        if options.step {
+            println!("line {}, at {}: {:?}", number, record.time, record.event);
+        }
+        if let Err(e) = replay.apply(&record.event) {
+            problems += 1;
+            println!("line {}: {:?} disagrees: {}", number, record.event, e);
+        }
+
+        if options.step {
+            print_game(&replay);
+            let mut input = String::new();
+            if stdin.lock().read_line(&mut input).unwrap_or(0) == 0 || input.trim() == "q" {
+                complete = false;
+                break;
+            }
+        }
+    }
+
+    // a log cut off part way through a round is only a problem once it has ended
+    if complete {
+        if let Err(e) = replay.finish() {
+            problems += 1;
+            println!("end of log: {}", e);
+        }
+    }
+
+    print_game(&replay);
+    println!("{} problems found", problems);
+    if problems > 0 {
+        process::exit(2);
+    }
+}
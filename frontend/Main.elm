@@ -5,8 +5,9 @@ import Css.Colors exposing (aqua, gray, silver)
 import Html exposing (..)
 import Html.Attributes exposing (src, style)
 import Html.Events exposing (onClick)
+import Http
 import Json.Decode exposing (Decoder, decodeString)
-import Json.Decode.Pipeline exposing (decode, required)
+import Json.Decode.Pipeline exposing (decode, optional, required)
 import WebSocket
 
 
@@ -123,21 +124,123 @@ boardDecoder =
         )
 
 
-type alias NetworkMessage =
-    { board : List (List (Maybe Piece)), turn : PieceColour }
+type alias StateUpdate =
+    { board : List (List (Maybe Piece)), turn : PieceColour, sequence : Int }
+
+
+type alias SquareChange =
+    { x : Int, y : Int, piece : Maybe Piece }
+
+
+type alias StateDelta =
+    { base : Int, sequence : Int, turn : PieceColour, changes : List SquareChange }
+
+
+type NetworkMessage
+    = FullState StateUpdate
+    | DeltaState StateDelta
+    | CallVote Int
+
+
+squareChangeDecoder : Decoder SquareChange
+squareChangeDecoder =
+    decode SquareChange
+        |> required "x" Json.Decode.int
+        |> required "y" Json.Decode.int
+        |> required "piece" (Json.Decode.nullable pieceDecoder)
 
 
 messageDecoder : Decoder NetworkMessage
 messageDecoder =
-    decode NetworkMessage
-        |> required "board" boardDecoder
-        |> required "turn" colourDecoder
+    Json.Decode.andThen
+        (\x ->
+            case x of
+                "state_change" ->
+                    Json.Decode.map FullState
+                        (decode StateUpdate
+                            |> required "board" boardDecoder
+                            |> required "turn" colourDecoder
+                            |> optional "sequence" Json.Decode.int 0
+                        )
+
+                "state_delta" ->
+                    Json.Decode.map DeltaState
+                        (decode StateDelta
+                            |> required "base" Json.Decode.int
+                            |> required "sequence" Json.Decode.int
+                            |> required "turn" colourDecoder
+                            |> required "changes" (Json.Decode.list squareChangeDecoder)
+                        )
+
+                "vote_call" ->
+                    Json.Decode.map CallVote (Json.Decode.field "round" Json.Decode.int)
+
+                _ ->
+                    Json.Decode.fail ("Ignoring " ++ x ++ " message")
+        )
+        (Json.Decode.field "type" Json.Decode.string)
+
+
+applyChanges : List SquareChange -> List (List (Maybe Piece)) -> List (List (Maybe Piece))
+applyChanges changes board =
+    let
+        changed x y square =
+            changes
+                |> List.filter (\change -> change.x == x && change.y == y)
+                |> List.head
+                |> Maybe.map .piece
+                |> Maybe.withDefault square
+    in
+        List.indexedMap (\y row -> List.indexedMap (\x square -> changed x y square) row) board
+
+
+protocolVersion : Int
+protocolVersion =
+    3
+
+
+helloMessage : String
+helloMessage =
+    "{\"type\": \"hello\", \"version\": " ++ toString protocolVersion ++ ", \"deltas\": true}"
+
+
+resyncMessage : String
+resyncMessage =
+    "{\"type\": \"resync\"}"
+
+
+{-| A vote for a move in `voteRound`, which funnels drop once that round is closed.
+-}
+ballotMessage : List Int -> List Int -> Int -> String
+ballotMessage from to voteRound =
+    "{\"type\": \"ballot\", \"action\": {\"from\": " ++ toString from ++ ", \"to\": " ++ toString to ++ "}, \"round\": " ++ toString voteRound ++ "}"
+
+
+{-| Asks the gateway which funnel to connect to.
+-}
+gatewayUrl : String
+gatewayUrl =
+    "http://127.0.0.1:2830/connect"
+
+
+{-| Where to connect when the gateway can't be reached, as when running a single funnel.
+-}
+fallbackFunnelUrl : String
+fallbackFunnelUrl =
+    "ws://127.0.0.1:2828"
+
+
+assignFunnel : PieceColour -> Cmd Msg
+assignFunnel team =
+    Http.send (Assigned team) (Http.get gatewayUrl (Json.Decode.field "url" Json.Decode.string))
 
 
 type alias GameModel =
     { board : List (List (Maybe Piece))
     , self : PieceColour
     , turn : PieceColour
+    , sequence : Int
+    , round : Maybe Int
     , clickState : ClickState
     , url : String
     }
@@ -145,6 +248,7 @@ type alias GameModel =
 
 type Model
     = SelectingTeam
+    | Assigning PieceColour
     | Loading PieceColour String
     | InGame GameModel
 
@@ -157,6 +261,7 @@ type Msg
     = Chosen PieceColour
     | Click Int Int
     | Unclick
+    | Assigned PieceColour (Result Http.Error String)
     | Transmission String
 
 
@@ -166,7 +271,20 @@ update msg model =
         SelectingTeam ->
             (case msg of
                 Chosen x ->
-                    ( Loading x "ws://127.0.0.1:2828", Cmd.none )
+                    ( Assigning x, assignFunnel x )
+
+                _ ->
+                    ( model, Cmd.none )
+            )
+
+        Assigning team ->
+            (case msg of
+                Assigned _ result ->
+                    let
+                        url =
+                            Result.withDefault fallbackFunnelUrl result
+                    in
+                        ( Loading team url, WebSocket.send url helloMessage )
 
                 _ ->
                     ( model, Cmd.none )
@@ -176,10 +294,12 @@ update msg model =
             (case msg of
                 Transmission msg ->
                     (case decodeString messageDecoder msg of
-                        Ok update ->
+                        Ok (FullState update) ->
                             ( InGame
                                 { board = update.board
                                 , turn = update.turn
+                                , sequence = update.sequence
+                                , round = Nothing
                                 , url = url
                                 , self = team
                                 , clickState = Unselected
@@ -187,6 +307,10 @@ update msg model =
                             , Cmd.none
                             )
 
+                        Ok (DeltaState _) ->
+                            -- nothing to apply it to yet
+                            ( Loading team url, WebSocket.send url resyncMessage )
+
                         _ ->
                             -- error handling is for weenies part 2
                             ( Loading team url, Cmd.none )
@@ -205,19 +329,26 @@ update msg model =
                                 ( InGame { model | clickState = Selected x y }, Cmd.none )
 
                             Selected x0 y0 ->
-                                ( InGame { model | clickState = Done }
-                                , WebSocket.send model.url
-                                    (let
-                                        ( old, new ) =
-                                            case model.self of
-                                                White ->
-                                                    ( [ x0, y0 ], [ x, y ] )
-
-                                                Black ->
-                                                    ( [ 7 - x0, 7 - y0 ], [ 7 - x, 7 - y ] )
-                                     in
-                                        ("{\"action\": {\"from\": " ++ toString old ++ ", \"to\": " ++ toString new ++ "}, \"weight\": 1}")
-                                    )
+                                (case model.round of
+                                    Just voteRound ->
+                                        ( InGame { model | clickState = Done }
+                                        , WebSocket.send model.url
+                                            (let
+                                                ( old, new ) =
+                                                    case model.self of
+                                                        White ->
+                                                            ( [ x0, y0 ], [ x, y ] )
+
+                                                        Black ->
+                                                            ( [ 7 - x0, 7 - y0 ], [ 7 - x, 7 - y ] )
+                                             in
+                                                ballotMessage old new voteRound
+                                            )
+                                        )
+
+                                    Nothing ->
+                                        -- no vote is open to take the move
+                                        ( InGame { model | clickState = Unselected }, Cmd.none )
                                 )
 
                             _ ->
@@ -231,21 +362,17 @@ update msg model =
 
                 Transmission msg ->
                     (case decodeString messageDecoder msg of
-                        Ok update ->
-                            let
-                                newClickState =
-                                    if update.turn == model.self then
-                                        (case model.clickState of
-                                            Selected x y ->
-                                                Selected x y
-
-                                            _ ->
-                                                Unselected
-                                        )
-                                    else
-                                        Unselected
-                            in
-                                ( InGame { model | board = update.board, turn = update.turn, clickState = newClickState }, Cmd.none )
+                        Ok (FullState update) ->
+                            ( InGame (applyState update.board update.turn update.sequence model), Cmd.none )
+
+                        Ok (DeltaState delta) ->
+                            if delta.base == model.sequence then
+                                ( InGame (applyState (applyChanges delta.changes model.board) delta.turn delta.sequence model), Cmd.none )
+                            else
+                                ( InGame model, WebSocket.send model.url resyncMessage )
+
+                        Ok (CallVote voteRound) ->
+                            ( InGame { model | round = Just voteRound }, Cmd.none )
 
                         _ ->
                             -- error handling is for weenies
@@ -258,6 +385,32 @@ update msg model =
 
 
 
+applyState : List (List (Maybe Piece)) -> PieceColour -> Int -> GameModel -> GameModel
+applyState board turn sequence model =
+    let
+        newClickState =
+            if turn == model.self then
+                (case model.clickState of
+                    Selected x y ->
+                        Selected x y
+
+                    _ ->
+                        Unselected
+                )
+            else
+                Unselected
+
+        newRound =
+            -- a new position closes the round its vote call opened
+            if sequence == model.sequence then
+                model.round
+            else
+                Nothing
+    in
+        { model | board = board, turn = turn, sequence = sequence, round = newRound, clickState = newClickState }
+
+
+
 -- view
 
 
@@ -445,6 +598,9 @@ view model =
                     , button [ onClick (Chosen Black), buttonStyle ] [ text "I want to play for the black team" ]
                     ]
 
+        Assigning _ ->
+            text "Loading"
+
         Loading _ _ ->
             text "Loading"
 
@@ -462,6 +618,9 @@ subscriptions model =
         SelectingTeam ->
             Sub.none
 
+        Assigning _ ->
+            Sub.none
+
         Loading _ url ->
             WebSocket.listen url Transmission
 